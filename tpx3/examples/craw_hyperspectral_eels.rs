@@ -1,77 +1,38 @@
-use std::fs::File;
-use std::io::prelude::*;
-use timepix3::packetlib::*;
+use timepix3::simulatelib::*;
 use timepix3::tdclib::TdcType;
-use rand_distr::{Normal, Distribution};
-use rand::distributions::Uniform;
-use rand::{thread_rng};
 
 fn main() {
 
-    const ELE_PA: f64 = 6.25 / 1_000f64; //electrons / ns;
-
-    let total_time = 5_000_000_000; //total time in ns;
-    let xt = 256; // X spim;
-    let yt = 256; //Y spim;
-    let pdt: usize = 125; //pixel dwell time in ns;
-    let fb = 10_000; //flyback in ns;
-    let cur = 1; //electron current, in pA;
+    let total_time: u64 = 5_000_000_000; //total time in ns;
+    let xt: u32 = 256; // X spim;
+    let yt: u32 = 256; //Y spim;
+    let pdt: u64 = 125; //pixel dwell time in ns;
+    let fb: u64 = 10_000; //flyback in ns;
     let radius = xt/4; //The radius of the hole created, in pixels;
-    let mut t: usize = 0; //The time of the electron events. They will be incremented during the program run;
-    let mut counter: usize = 0; //Counter for the electron events;
-    let frames = total_time / (xt * yt * pdt + yt * fb); //Number of frames that will be created;
+    let frames = (total_time / ((xt * yt) as u64 * pdt + yt as u64 * fb)) as u32; //Number of frames that will be created;
     println!("Number of frames: {}.", frames);
 
-    //Random variables;
-    let col = Uniform::new(0.0, xt as f32); //Emplacing in the column. Something between 0 (left pixel) and xt (rightmost pixel);
-    let pick = Uniform::new(0.0, 1.0); //Uniform probability from 0-1. This is used to decide if the electron will fall in the ZLP or in the simulated resonance;
-    let zlp = Normal::new(128.0, 3.0).unwrap(); //Zero-loss peak. Centered at the pixel 128 and the std deviation is 3 pixels;
-    let exc = Normal::new(190.0, 4.0).unwrap(); //The resonance;
-
-    // This closure defines the electron emplacement. 90% of the electrons go to the ZLP.
-    let alpha = |prob: f32| {
-        if prob > 0.1 {
-            zlp.sample(&mut thread_rng())
-        } else {
-            exc.sample(&mut thread_rng())
-        }
-    };
-
-    let mut data: Vec<[u8; 16]> = Vec::new(); //Out TPX3 packets;
-    let mut line_counter = 0; //The line counter;
-    let mut electron: InversePacket; //The InversePacket struct. Defined in the packetlib;
-    let mut line_start: InversePacket; //Same but for the TDC.
-
-    for _frame in 0..frames { //Looping in the frames;
-        for line in 0..yt { //Looping in the lines;
-            line_counter += 1; //Line increment;
-            line_start = InversePacket::new_inverse_tdc(t); //Define TDC packet;
-            data.push(line_start.create_tdc_array(line_counter, TdcType::TdcOneFallingEdge)); //Push our line begin to our data;
-            for _ in 0..((pdt * xt * cur) as f64 * ELE_PA) as usize { //Looping in the number of electrons;
-                counter+=1; //Increment in the electron;
-                let x = col.sample(&mut rand::thread_rng()) as usize; //X gives you the column in the hyperspectrum
-                let disp = if circle(x, line, radius, (xt/2, yt/2)) { //disp gives you the dispersive value of the signal;
-                    alpha(pick.sample(&mut rand::thread_rng())) //Inside circle => use the closure alpha;
-                } else {
-                    zlp.sample(&mut thread_rng()) //Outside circle => falls in the ZLP;
-                };
-                electron = InversePacket::new_inverse_electron(disp as usize, 128, t+x*pdt); //creating the electron packet;
-                data.push(electron.create_electron_array()); //Pushing to our data;
-            }
-            line_counter += 1; //Line increment;
-            line_start = InversePacket::new_inverse_tdc(t + pdt*xt); //This is actually line end;
-            data.push(line_start.create_tdc_array(line_counter, TdcType::TdcOneRisingEdge)); //Push the end of a line to our data; 
-            t = t + pdt * xt + fb;
-        }
-    }
-    
-    let final_vec = data.iter().map(|&x| x).flatten().collect::<Vec<_>>();
-    let mut file = File::create("Data/raw000000.tpx3").unwrap();
-    file.write_all(&final_vec).expect("Problem exporting data in dummy hyperspectral EELS");
-
-    println!("Total time: {} and Electrons: {}. Ratio is (e/ns) {}", t, counter, counter as f64 / t as f64);
+    //Zero-loss peak centered at the pixel 128. Inside the circle, 10% of the electrons fall in the resonance.
+    let zlp = Peak::new(0.9, 128.0, 3.0);
+    let exc = Peak::new(0.1, 190.0, 4.0);
+    let circle = move |x: u32, y: u32| circle(x, y, radius, (xt/2, yt/2));
+
+    let summary = SimulationBuilder::new()
+        .scan(xt, yt, pdt as f64, fb as f64)
+        .frames(frames)
+        .beam_current(1.0)
+        .spectrum(Spectrum::new(128.0, 0.0).peak(zlp))
+        .spectrum_where(circle, Spectrum::new(128.0, 0.0).peak(zlp).peak(exc))
+        .line_tdc(TdcType::TdcOneFallingEdge)
+        .build()
+        .expect("Bad simulation settings.")
+        .write_file("Data/raw000000.tpx3")
+        .expect("Problem exporting data in dummy hyperspectral EELS");
+
+    println!("Total time: {} and Electrons: {}. Ratio is (e/ns) {}", summary.duration, summary.electrons, summary.electrons as f64 / summary.duration);
 }
 
-fn circle(x: usize, y: usize, radius: usize, c: (usize, usize)) -> bool {
-    (x-c.0)*(x-c.0)+(y-c.1)*(y-c.1)<radius*radius
+fn circle(x: u32, y: u32, radius: u32, c: (u32, u32)) -> bool {
+    let (dx, dy) = (x as i64 - c.0 as i64, y as i64 - c.1 as i64);
+    dx*dx+dy*dy < (radius*radius) as i64
 }
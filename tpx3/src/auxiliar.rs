@@ -1,16 +1,27 @@
 //!`auxiliar` is a collection of tools to set acquisition conditions.
 use crate::errorlib::Tp3ErrorKind;
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::net::{TcpListener, SocketAddr};
 use crate::auxiliar::misc::TimepixRead;
 use std::io::{Read, Write};
 use std::fs::File;
 use crate::auxiliar::value_types::*;
+use crate::tdclib::{TdcType, TdcRefKind, TdcSearchOptions, TdcRefSpec, ReferenceTdcs};
+use crate::timelib::TimeBase;
+use crate::modelib::AcquisitionMode;
+use serde::{Deserialize, Serialize};
+use crate::recordlib::Recorder;
+use crate::controllib::AcquisitionControl;
+use crate::broadcastlib::Broadcaster;
+use crate::layoutlib::DetectorLayout;
+use crate::spimlib::{VirtualDetector, VirtualDetectors};
 //use std::{fs::{File, OpenOptions, create_dir_all}, path::Path};
 
 const CONFIG_SIZE: usize = 16;
+const SETTINGS_MAGIC: [u8; 4] = *b"TP3S";
+const SETTINGS_MAX_SIZE: usize = 65_536;
 
 ///Configures the detector for acquisition. Each new measurement must send 20 bytes
-///containing instructions.
+///containing instructions. This is the version 0 of the settings handshake (see `read_settings`).
 struct BytesConfig {
     pub data: [u8; CONFIG_SIZE],
 }
@@ -151,17 +162,9 @@ impl BytesConfig {
         let xspim = (self.data[4] as POSITION)<<8 | (self.data[5] as POSITION);
         let xscan = (self.data[8] as POSITION)<<8 | (self.data[9] as POSITION);
         if xspim == 0 {return Err(Tp3ErrorKind::SetXSize);}
-        let var = xscan / xspim;
-        match var {
-            0 => {
-                println!("Xratio is: 1.");
-                Ok(1)
-            },
-            _ => {
-                println!("Xratio is: {}.", var);
-                Ok(var)
-            },
-        }
+        let var = spim_over_scan(xspim, xscan);
+        println!("Xratio is: {}.", var);
+        Ok(var)
     }
     
     ///Convenience method. Returns the ratio between scan and spim size in Y.
@@ -169,27 +172,21 @@ impl BytesConfig {
         let yspim = (self.data[6] as POSITION)<<8 | (self.data[7] as POSITION);
         let yscan = (self.data[10] as POSITION)<<8 | (self.data[11] as POSITION);
         if yspim == 0 {return Err(Tp3ErrorKind::SetYSize);}
-        let var = yscan / yspim;
-        match var {
-            0 => {
-                println!("Yratio is: 1.");
-                Ok(1)
-            },
-            _ => {
-                println!("Yratio is: {}.", var);
-                Ok(var)
-            },
-        }
+        let var = spim_over_scan(yspim, yscan);
+        println!("Yratio is: {}.", var);
+        Ok(var)
     }
 
 
     ///Create Settings struct from BytesConfig
     fn create_settings(&self) -> Result<Settings, Tp3ErrorKind> {
+        let mode = self.mode()?;
+        let (frame_tdc, ref_tdc, ref_tdc_kind) = Settings::default_tdc(mode);
         let my_set = Settings {
             bin: self.bin()?,
             bytedepth: self.bytedepth()?,
             cumul: self.cumul()?,
-            mode: self.mode()?,
+            mode,
             xspim_size: self.xspim_size(),
             yspim_size: self.yspim_size(),
             xscan_size: self.xscan_size(),
@@ -198,10 +195,180 @@ impl BytesConfig {
             time_width: self.time_width(),
             spimoverscanx: self.spimoverscanx()?,
             spimoverscany: self.spimoverscany()?,
+            frame_tdc,
+            ref_tdc,
+            ref_tdc_kind,
+            tdc_search: TdcSearchOptions::default(),
+            time_base: TimeBase::default(),
+            reference_tdcs: ReferenceTdcs::default(),
+            virtual_detectors: VirtualDetectors::default(),
+        };
+        Ok(my_set)
+    }
+
+}
+
+///Ratio between scan and spim size. Never smaller than 1.
+fn spim_over_scan(spim: POSITION, scan: POSITION) -> POSITION {
+    match scan / spim {
+        0 => 1,
+        var => var,
+    }
+}
+
+///Settings sent using the versioned handshake (version 1). The payload is a JSON object whose
+///keys are the `Settings` field names. Absent keys take the default values below and unknown keys
+///are ignored, so newer clients can talk to older servers. TDC keys take the enum variant names,
+///e.g. `"frame_tdc": "TdcOneFallingEdge"` or `"ref_tdc_kind": "SingleTriggerPeriodic"`, and the
+///reference search is tuned with `"tdc_search": {"samples": 3, "timeout": 10.0}`. `"time_base": "Tdc"`
+///keeps the 260 ps precision of the reference TDC in the time-resolved modes. More reference TDCs
+///are given as `"reference_tdcs": [{"tdc": "TdcTwoFallingEdge", "role": "Photon"}]`. Virtual
+///detectors are given as `"virtual_detectors": [{"kind": "annular", "center": [256, 256], "inner": 0, "outer": 30},
+///{"kind": "center_of_mass"}]`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct JsonConfig {
+    bin: bool,
+    bytedepth: POSITION,
+    cumul: bool,
+    mode: u8,
+    xspim_size: POSITION,
+    yspim_size: POSITION,
+    xscan_size: POSITION,
+    yscan_size: POSITION,
+    time_delay: TIME,
+    time_width: TIME,
+    frame_tdc: Option<TdcType>,
+    ref_tdc: Option<TdcType>,
+    ref_tdc_kind: Option<TdcRefKind>,
+    tdc_search: TdcSearchOptions,
+    time_base: TimeBase,
+    reference_tdcs: Vec<TdcRefSpec>,
+    virtual_detectors: Vec<VirtualDetector>,
+}
+
+impl Default for JsonConfig {
+    fn default() -> Self {
+        JsonConfig {
+            bin: true,
+            bytedepth: 4,
+            cumul: false,
+            mode: 0,
+            xspim_size: 1,
+            yspim_size: 1,
+            xscan_size: 1,
+            yscan_size: 1,
+            time_delay: 0,
+            time_width: 0,
+            frame_tdc: None,
+            ref_tdc: None,
+            ref_tdc_kind: None,
+            tdc_search: TdcSearchOptions::default(),
+            time_base: TimeBase::default(),
+            reference_tdcs: Vec::new(),
+            virtual_detectors: Vec::new(),
+        }
+    }
+}
+
+impl JsonConfig {
+    ///Create Settings struct from JsonConfig
+    fn create_settings(&self) -> Result<Settings, Tp3ErrorKind> {
+        match self.bytedepth {
+            1 | 2 | 4 => {},
+            _ => return Err(Tp3ErrorKind::SetByteDepth),
+        }
+        if self.xspim_size == 0 {return Err(Tp3ErrorKind::SetXSize);}
+        if self.yspim_size == 0 {return Err(Tp3ErrorKind::SetYSize);}
+        if !(self.tdc_search.timeout.is_finite() && self.tdc_search.timeout >= 0.0) {return Err(Tp3ErrorKind::SetBadSettings);}
+        let (frame_tdc, ref_tdc, ref_tdc_kind) = Settings::default_tdc(self.mode);
+        let (frame_tdc, ref_tdc) = (self.frame_tdc.unwrap_or(frame_tdc), self.ref_tdc.unwrap_or(ref_tdc));
+        let my_set = Settings {
+            bin: self.bin,
+            bytedepth: self.bytedepth,
+            cumul: self.cumul,
+            mode: self.mode,
+            xspim_size: self.xspim_size,
+            yspim_size: self.yspim_size,
+            xscan_size: self.xscan_size,
+            yscan_size: self.yscan_size,
+            time_delay: self.time_delay,
+            time_width: self.time_width,
+            spimoverscanx: spim_over_scan(self.xspim_size, self.xscan_size),
+            spimoverscany: spim_over_scan(self.yspim_size, self.yscan_size),
+            frame_tdc,
+            ref_tdc,
+            ref_tdc_kind: self.ref_tdc_kind.unwrap_or(ref_tdc_kind),
+            tdc_search: self.tdc_search,
+            time_base: self.time_base,
+            reference_tdcs: ReferenceTdcs::new(&self.reference_tdcs, &[frame_tdc, frame_tdc.opposite_edge(), ref_tdc])?,
+            virtual_detectors: VirtualDetectors::new(&self.virtual_detectors)?,
         };
         Ok(my_set)
     }
+}
+
+///Framed message holding the acquisition settings (see `read_settings`).
+pub const MESSAGE_SETTINGS: u8 = 1;
+///Framed message sent by the client during a measurement (see `controllib`).
+pub const MESSAGE_CONTROL: u8 = 2;
+///Framed message sent to the client once a measurement is over (see `controllib`).
+pub const MESSAGE_SUMMARY: u8 = 3;
+
+///Reads the acquisition settings sent by the client.
+///
+///Version 0 is the legacy 16-byte `BytesConfig` layout. Every later version is a framed message: the
+///four bytes `TP3S`, followed by the message type (1 byte) and the payload size (u32, big-endian).
+///Settings use type `MESSAGE_SETTINGS`, whose payload is the JSON object described in `JsonConfig`.
+///The legacy layout never starts with `TP3S` because its first byte is the binning flag (`\x00` or `\x01`).
+pub fn read_settings<R: Read>(sock: &mut R) -> Result<Settings, Tp3ErrorKind> {
+    let mut cam_settings = [0_u8; CONFIG_SIZE];
+    sock.read_exact(&mut cam_settings[0..4]).map_err(|_| Tp3ErrorKind::SetNoSettings)?;
+
+    if cam_settings[0..4] != SETTINGS_MAGIC {
+        sock.read_exact(&mut cam_settings[4..]).map_err(|_| Tp3ErrorKind::SetNoSettings)?;
+        println!("Received settings (version 0) is {:?}.", cam_settings);
+        return BytesConfig{data: cam_settings}.create_settings();
+    }
+
+    let (version, payload) = read_message_body(sock)?;
+    match version {
+        MESSAGE_SETTINGS => {
+            let config: JsonConfig = serde_json::from_slice(&payload).map_err(|_| Tp3ErrorKind::SetBadSettings)?;
+            println!("Received settings (version 1) is {:?}.", config);
+            config.create_settings()
+        },
+        _ => Err(Tp3ErrorKind::SetVersion(version)),
+    }
+}
 
+///Reads a framed message. Returns its type and payload.
+pub(crate) fn read_message<R: Read>(sock: &mut R) -> Result<(u8, Vec<u8>), Tp3ErrorKind> {
+    let mut magic = [0_u8; 4];
+    sock.read_exact(&mut magic).map_err(|_| Tp3ErrorKind::SetNoSettings)?;
+    if magic != SETTINGS_MAGIC {return Err(Tp3ErrorKind::SetBadSettings);}
+    read_message_body(sock)
+}
+
+fn read_message_body<R: Read>(sock: &mut R) -> Result<(u8, Vec<u8>), Tp3ErrorKind> {
+    let mut header = [0_u8; 5];
+    sock.read_exact(&mut header).map_err(|_| Tp3ErrorKind::SetNoSettings)?;
+    let version = header[0];
+    let size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if size > SETTINGS_MAX_SIZE {return Err(Tp3ErrorKind::SetBadSettings);}
+
+    let mut payload = vec![0_u8; size];
+    sock.read_exact(&mut payload).map_err(|_| Tp3ErrorKind::SetNoSettings)?;
+    Ok((version, payload))
+}
+
+///Writes a framed message.
+pub(crate) fn write_message<W: Write>(sock: &mut W, version: u8, payload: &[u8]) -> std::io::Result<()> {
+    sock.write_all(&SETTINGS_MAGIC)?;
+    sock.write_all(&[version])?;
+    sock.write_all(&(payload.len() as u32).to_be_bytes())?;
+    sock.write_all(payload)?;
+    sock.flush()
 }
 
 
@@ -223,7 +390,7 @@ impl Read for DebugIO {
 
 
 ///`Settings` contains all relevant parameters for a given acquistion
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize)]
 pub struct Settings {
     pub bin: bool,
     pub bytedepth: POSITION,
@@ -237,59 +404,68 @@ pub struct Settings {
     pub time_width: TIME,
     pub spimoverscanx: POSITION,
     pub spimoverscany: POSITION,
+    pub frame_tdc: TdcType,
+    pub ref_tdc: TdcType,
+    pub ref_tdc_kind: TdcRefKind,
+    ///Sample count and timeout of the TDC reference search.
+    pub tdc_search: TdcSearchOptions,
+    ///Time base of the reference TDC, and of `time_delay` and `time_width`. Frame and line TDCs are
+    ///always in the electron base.
+    pub time_base: TimeBase,
+    ///Reference TDCs used besides `ref_tdc`, each with its role.
+    pub reference_tdcs: ReferenceTdcs,
+    ///Virtual detectors of the 4D-STEM modes.
+    pub virtual_detectors: VirtualDetectors,
 }
 
 impl Settings {
 
-    ///Create Settings structure reading from a TCP.
-    pub fn create_settings(host_computer: [u8; 4], port: u16) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>), Tp3ErrorKind> {
+    ///TDC wiring used when the client does not choose one (version 0 clients never do). It is
+    ///registered with each mode in `modelib`. Unknown modes get the focus wiring and fail later.
+    pub fn default_tdc(mode: u8) -> (TdcType, TdcType, TdcRefKind) {
+        match AcquisitionMode::find(mode) {
+            Ok(entry) => entry.tdc,
+            Err(_) => (TdcType::TdcOneRisingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        }
+    }
+
+    ///Create Settings structure reading from a TCP. Frames sent to the client are also published to `broadcaster`.
+    pub fn create_settings(config: &NetworkConfig, broadcaster: &Broadcaster) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>), Tp3ErrorKind> {
     
-        let mut _sock_vec: Vec<TcpStream> = Vec::new();
-        
-        let addrs = [
-            SocketAddr::from((host_computer, port)),
-            SocketAddr::from(([127, 0, 0, 1], port)),
-        ];
-        
-        let pack_listener = TcpListener::bind("127.0.0.1:8098").expect("Could not bind to TP3.");
-        let ns_listener = TcpListener::bind(&addrs[..]).expect("Could not bind to NS.");
+        let pack_listener = TcpListener::bind(config.packet_addr).map_err(|_| Tp3ErrorKind::NetBind(config.packet_addr))?;
+        let ns_listener = TcpListener::bind(&config.ns_addrs[..]).map_err(|_| Tp3ErrorKind::NetBind(config.ns_addrs[0]))?;
         println!("Packet Tcp socket connected at: {:?}", pack_listener);
         println!("Nionswift Tcp socket connected at: {:?}", ns_listener);
 
         let debug: bool = match ns_listener.local_addr() {
-            Ok(val) if val == addrs[1] => true,
+            Ok(val) if val != config.ns_addrs[0] && val.ip().is_loopback() => true,
             _ => false,
         };
 
-        let (mut ns_sock, ns_addr) = ns_listener.accept().expect("Could not connect to Nionswift.");
+        let (mut ns_sock, ns_addr) = ns_listener.accept().map_err(|_| Tp3ErrorKind::NetAccept)?;
         println!("Nionswift connected at {:?} and {:?}.", ns_addr, ns_sock);
         
-        let mut cam_settings = [0_u8; CONFIG_SIZE];
-        let my_config = {
-            match ns_sock.read(&mut cam_settings){
-                Ok(size) => {
-                    println!("Received {} bytes from NS.", size);
-                    BytesConfig{data: cam_settings}
-                },
-                Err(_) => panic!("Could not read cam initial settings."),
-            }
-        };
-        let my_settings = my_config.create_settings()?;
-        println!("Received settings is {:?}. Mode is {}.", cam_settings, my_settings.mode);
+        let my_settings = read_settings(&mut ns_sock)?;
+        println!("Received settings is {:?}. Mode is {}.", my_settings, my_settings.mode);
 
-        //This is a special case. This mode saves locally so we do not need to ready
+        //This is a special case. These modes save locally so we do not need to ready
         //anything special. We do not write anything special as well, so we do not need
         //to return the ns_sock.
-        if my_settings.mode == 8 {
-            println!("Special mode 8. Save locally but using the IsiBox system.");
+        if AcquisitionMode::find(my_settings.mode).is_ok_and(|entry| entry.local_only) {
+            println!("Special mode {}. Save locally but using the IsiBox system.", my_settings.mode);
             return Ok((my_settings, Box::new(DebugIO{}), Box::new(DebugIO{})));
         }
 
         match debug {
             false => {
-                let (pack_sock, packet_addr) = pack_listener.accept().expect("Could not connect to TP3.");
+                let (pack_sock, packet_addr) = pack_listener.accept().map_err(|_| Tp3ErrorKind::NetAccept)?;
                 println!("Localhost TP3 detected at {:?} and {:?}.", packet_addr, pack_sock);
-                Ok((my_settings, Box::new(pack_sock), Box::new(ns_sock)))
+                let control = AcquisitionControl::listen(&ns_sock, Some(&pack_sock))?;
+                let pack: Box<dyn misc::TimepixRead + Send> = match &config.record_dir {
+                    Some(dir) => Box::new(Recorder::new(pack_sock, dir, &my_settings, config.record_file_size)?),
+                    None => Box::new(pack_sock),
+                };
+                Ok((my_settings, Box::new(control.reader(pack)), Box::new(control.writer(broadcaster.frame_writer(ns_sock)))))
             },
             true => {
                 let file = match File::open("bin/Data/raw000000.tpx3") {
@@ -297,7 +473,8 @@ impl Settings {
                     Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
                 };
                 println!("Debug mode. Will one file a single time.");
-                Ok((my_settings, Box::new(file), Box::new(ns_sock)))
+                let control = AcquisitionControl::listen(&ns_sock, None)?;
+                Ok((my_settings, Box::new(control.reader(file)), Box::new(control.writer(broadcaster.frame_writer(ns_sock)))))
             },
         }
 
@@ -317,6 +494,13 @@ impl Settings {
             time_width: 1000,
             spimoverscanx: 1,
             spimoverscany: 1,
+            frame_tdc: TdcType::TdcOneRisingEdge,
+            ref_tdc: TdcType::TdcTwoFallingEdge,
+            ref_tdc_kind: TdcRefKind::NonPeriodic,
+            tdc_search: TdcSearchOptions::default(),
+            time_base: TimeBase::default(),
+            reference_tdcs: ReferenceTdcs::default(),
+            virtual_detectors: VirtualDetectors::default(),
         }
     }
     
@@ -334,6 +518,13 @@ impl Settings {
             time_width: 1000,
             spimoverscanx: 1,
             spimoverscany: 1,
+            frame_tdc: TdcType::TdcOneFallingEdge,
+            ref_tdc: TdcType::TdcTwoFallingEdge,
+            ref_tdc_kind: TdcRefKind::NonPeriodic,
+            tdc_search: TdcSearchOptions::default(),
+            time_base: TimeBase::default(),
+            reference_tdcs: ReferenceTdcs::default(),
+            virtual_detectors: VirtualDetectors::default(),
         }
     }
 
@@ -359,6 +550,14 @@ impl Settings {
 }
 
 ///`ConfigAcquisition` is used for post-processing, where reading external TPX3 files is necessary.
+///An optional sixth argument is a time calibration file (see `calibrationlib`), applied by the
+///coincidence search, and an optional seventh is the folder of the energy calibration, giving the
+///energy of each `SingleElectron`. A `-` skips the time calibration. The calibrations are only read
+///here; binaries set them with `calibrationlib::set_time_calibration` and `set_energy_calibration`.
+///
+///The detector layout is given, as for the live servers, by `--layout` (a preset name or a JSON file)
+///or `TP3_LAYOUT`. `--energy-window <min>,<max>` keeps the electrons (or clusters) whose energy, in
+///keV, is in the window. It needs the energy calibration.
 #[derive(Debug)]
 pub struct ConfigAcquisition {
     pub file: String,
@@ -366,6 +565,10 @@ pub struct ConfigAcquisition {
     pub xspim: POSITION,
     pub yspim: POSITION,
     pub remove_cluster: bool,
+    pub time_calibration: Option<String>,
+    pub energy_calibration: Option<String>,
+    pub layout: DetectorLayout,
+    pub energy_window: Option<(f32, f32)>,
 }
 
 impl ConfigAcquisition {
@@ -373,25 +576,150 @@ impl ConfigAcquisition {
         &self.file
     }
 
-    pub fn new(args: &[String]) -> Self {
-        if args.len() != 5+1 {
-            panic!("One must provide 5 ({} detected) arguments (file, is_spim, xspim, yspim, remove_cluster).", args.len()-1);
+    pub fn new(args: &[String]) -> Result<Self, Tp3ErrorKind> {
+        let mut args = args.to_vec();
+        let layout = DetectorLayout::take_arg(&mut args)?;
+        let energy_window = match args.iter().position(|arg| arg == "--energy-window") {
+            Some(index) => {
+                let value = args.get(index+1).cloned().ok_or(Tp3ErrorKind::SetArgument)?;
+                args.drain(index..index+2);
+                let (min, max) = value.split_once(',').ok_or(Tp3ErrorKind::SetArgument)?;
+                let parse = |value: &str| value.trim().parse::<f32>().map_err(|_| Tp3ErrorKind::SetArgument);
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min.is_nan() || max.is_nan() || min > max {return Err(Tp3ErrorKind::SetArgument);}
+                Some((min, max))
+            },
+            None => None,
+        };
+        if !(5+1..=7+1).contains(&args.len()) {
+            println!("One must provide 5 ({} detected) arguments (file, is_spim, xspim, yspim, remove_cluster) and optionally the time and energy calibrations.", args.len().saturating_sub(1));
+            return Err(Tp3ErrorKind::SetArgument);
         }
         let file = args[1].clone();
         let is_spim = args[2] == "1";
-        let xspim = args[3].parse::<POSITION>().unwrap();
-        let yspim = args[4].parse::<POSITION>().unwrap();
+        let xspim = args[3].parse::<POSITION>().map_err(|_| Tp3ErrorKind::SetArgument)?;
+        let yspim = args[4].parse::<POSITION>().map_err(|_| Tp3ErrorKind::SetArgument)?;
         let remove_cluster = args[5] == "1";
+        let time_calibration = args.get(6).filter(|path| *path != "-").cloned();
+        let energy_calibration = args.get(7).cloned();
+        if energy_window.is_some() && energy_calibration.is_none() {return Err(Tp3ErrorKind::SetArgument);}
         let my_config = 
         ConfigAcquisition {
             file,
             is_spim,
             xspim,
             yspim,
-            remove_cluster
+            remove_cluster,
+            time_calibration,
+            energy_calibration,
+            layout,
+            energy_window,
         };
         println!("Configuration for the coincidence measurement is {:?}", my_config);
-        my_config
+        Ok(my_config)
+    }
+}
+
+///`NetworkConfig` holds the addresses and recording options of the live servers. Values are taken,
+///in increasing priority, from the defaults, a JSON file, environment variables and command-line flags.
+///
+///The JSON file is given by `--config <file>` or `TP3_CONFIG` and may set any of the fields:
+///`{"packet_addr": "127.0.0.1:8098", "ns_addrs": ["192.168.199.11:8088", "127.0.0.1:8088"], "isibox_addr": "192.168.198.10:9592"}`.
+///The layout is `"layout": "quad"` or, for instance, `"layout": {"chips": [{"column": 0, "row": 0, "flip_x": true}], "gap": 2}`.
+///The environment variables are `TP3_PACKET_ADDR`, `TP3_NS_ADDRS` (comma-separated), `TP3_ISIBOX_ADDR`,
+///`TP3_BROADCAST_ADDR`, `TP3_STATS_ADDR`, `TP3_RECORD_DIR`, `TP3_RECORD_FILE_SIZE` and `TP3_LAYOUT`. The
+///flags are `--packet-addr`, `--ns-addrs` (comma-separated), `--isibox-addr`, `--broadcast-addr`,
+///`--stats-addr`, `--record-dir`, `--record-file-size` and `--layout` (a preset name or a JSON file).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    ///Listener for the Timepix3 packet stream.
+    pub packet_addr: SocketAddr,
+    ///Listener for the client. Addresses are tried in order; falling back to a loopback address
+    ///enables the file debug mode.
+    pub ns_addrs: Vec<SocketAddr>,
+    ///IsiBox server.
+    pub isibox_addr: SocketAddr,
+    ///Listener for extra consumers of the live frames (see `broadcastlib`). Disabled if absent.
+    pub broadcast_addr: Option<SocketAddr>,
+    ///Listener for the per-frame statistics (see `statslib`). Disabled if absent.
+    pub stats_addr: Option<SocketAddr>,
+    ///Folder in which the raw stream of live measurements is recorded (see `recordlib`). Disabled if absent.
+    pub record_dir: Option<String>,
+    ///Size, in bytes, above which a new raw file is started.
+    pub record_file_size: u64,
+    ///Chip placement on the detector (see `layoutlib`). Either a preset name or the list of chips.
+    pub layout: DetectorLayout,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            packet_addr: SocketAddr::from(([127, 0, 0, 1], 8098)),
+            ns_addrs: vec![SocketAddr::from(([192, 168, 199, 11], 8088)), SocketAddr::from(([127, 0, 0, 1], 8088))],
+            isibox_addr: SocketAddr::from(([192, 168, 198, 10], 9592)),
+            broadcast_addr: None,
+            stats_addr: None,
+            record_dir: None,
+            record_file_size: 1 << 30,
+            layout: DetectorLayout::default(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    ///Builds the configuration from the program arguments (`args[0]` is the program name).
+    pub fn new(args: &[String]) -> Result<Self, Tp3ErrorKind> {
+        let flag = |name: &str| -> Result<Option<String>, Tp3ErrorKind> {
+            match args.iter().position(|arg| arg == name) {
+                Some(index) => args.get(index+1).cloned().map(Some).ok_or(Tp3ErrorKind::SetNetworkConfig),
+                None => Ok(None),
+            }
+        };
+        let var = |name: &str| std::env::var(name).ok();
+
+        let mut config = match flag("--config")?.or_else(|| var("TP3_CONFIG")) {
+            Some(path) => Self::from_file(&path)?,
+            None => NetworkConfig::default(),
+        };
+
+        if let Some(value) = flag("--packet-addr")?.or_else(|| var("TP3_PACKET_ADDR")) {
+            config.packet_addr = Self::parse_addr(&value)?;
+        }
+        if let Some(value) = flag("--ns-addrs")?.or_else(|| var("TP3_NS_ADDRS")) {
+            config.ns_addrs = value.split(',').map(Self::parse_addr).collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = flag("--isibox-addr")?.or_else(|| var("TP3_ISIBOX_ADDR")) {
+            config.isibox_addr = Self::parse_addr(&value)?;
+        }
+        if let Some(value) = flag("--broadcast-addr")?.or_else(|| var("TP3_BROADCAST_ADDR")) {
+            config.broadcast_addr = Some(Self::parse_addr(&value)?);
+        }
+        if let Some(value) = flag("--stats-addr")?.or_else(|| var("TP3_STATS_ADDR")) {
+            config.stats_addr = Some(Self::parse_addr(&value)?);
+        }
+        if let Some(value) = flag("--record-dir")?.or_else(|| var("TP3_RECORD_DIR")) {
+            config.record_dir = Some(value);
+        }
+        if let Some(value) = flag("--record-file-size")?.or_else(|| var("TP3_RECORD_FILE_SIZE")) {
+            config.record_file_size = value.trim().parse().map_err(|_| Tp3ErrorKind::SetNetworkConfig)?;
+        }
+        if let Some(value) = flag("--layout")?.or_else(|| var("TP3_LAYOUT")) {
+            config.layout = DetectorLayout::from_arg(&value)?;
+        }
+        if config.ns_addrs.is_empty() {return Err(Tp3ErrorKind::SetNetworkConfig);}
+
+        println!("Network configuration is {:?}.", config);
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, Tp3ErrorKind> {
+        let file = File::open(path).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+        serde_json::from_reader(file).map_err(|_| Tp3ErrorKind::SetNetworkConfig)
+    }
+
+    fn parse_addr(value: &str) -> Result<SocketAddr, Tp3ErrorKind> {
+        value.trim().parse().map_err(|_| Tp3ErrorKind::SetNetworkConfig)
     }
 }
 
@@ -490,7 +818,17 @@ pub mod misc {
         }
     }
 
-    impl<R: Read + ?Sized> TimepixRead for Box<R> {}
+    impl<R: TimepixRead + ?Sized> TimepixRead for Box<R> {
+        fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+            (**self).read_timepix(buf)
+        }
+    }
+
+    impl<R: TimepixRead + ?Sized> TimepixRead for &mut R {
+        fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+            (**self).read_timepix(buf)
+        }
+    }
     impl TimepixRead for TcpStream {}
     impl TimepixRead for File {}
 }
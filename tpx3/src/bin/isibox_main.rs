@@ -1,53 +1,39 @@
 use timepix3::errorlib::Tp3ErrorKind;
 use timepix3::auxiliar::*;
-use timepix3::tdclib::{*, isi_box::*};
-//use timepix3::tdclib::{TdcControl, PeriodicTdcRef, isi_box, isi_box::{CHANNELS, IsiBoxTools, IsiBoxHand}};
-use timepix3::{speclib, speclib::{SpecKind, IsiBoxKind}, spimlib, spimlib::SpimKind};
-use timepix3::isi_box_new;
-use std::{thread, time};
+use timepix3::modelib::AcquisitionMode;
+use timepix3::broadcastlib::Broadcaster;
+use timepix3::statslib;
+use timepix3::layoutlib;
+use std::{env, thread, time};
 
 
-fn connect_and_loop() -> Result<u8, Tp3ErrorKind> {
+fn connect_and_loop(config: &NetworkConfig, broadcaster: &Broadcaster) -> Result<u8, Tp3ErrorKind> {
     
-    let (my_settings, mut pack, ns) = Settings::create_settings([192, 168, 199, 11], 8088)?;
-
-    match my_settings.mode {
-        0 if my_settings.bin => {
-            let meas = speclib::SpecMeasurement::<speclib::Live1D, u32>::isi_new(&my_settings);
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::build_spectrum_isi(pack, ns, my_settings, frame_tdc, np_tdc, meas)?;
-            Ok(my_settings.mode)
-        },
-        2 => {
-            let spim_tdc = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut pack, Some(my_settings.yspim_size))?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            let measurement = spimlib::Live::new();
-            spimlib::build_spim_isi(pack, ns, my_settings, spim_tdc, np_tdc, measurement)?;
-            Ok(my_settings.mode)
-        },
-        8 => {
-            let mut handler = isi_box_new!(spec);
-            handler.bind_and_connect();
-            handler.configure_scan_parameters(32, 32, 8334);
-            handler.configure_measurement_type(true);
-            thread::sleep(time::Duration::from_millis(1000));
-            Ok(my_settings.mode)
-        },
-        _ => Err(Tp3ErrorKind::IsiBoxAttempt(my_settings.mode)),
+    let (my_settings, pack, ns) = Settings::create_settings(config, broadcaster)?;
+    match AcquisitionMode::find(my_settings.mode) {
+        Ok(entry) => entry.run_isi(pack, ns, my_settings, config.isibox_addr),
+        Err(_) => Err(Tp3ErrorKind::IsiBoxAttempt(my_settings.mode)),
     }
 }
 
-fn main() {
+fn main() -> Result<(), Tp3ErrorKind> {
+    let args: Vec<String> = env::args().collect();
+    let config = NetworkConfig::new(&args)?;
+    layoutlib::set_layout(config.layout.clone())?;
+    let broadcaster = Broadcaster::new();
+    if let Some(addr) = config.broadcast_addr {broadcaster.listen(addr)?;}
+    if let Some(addr) = config.stats_addr {statslib::listen(addr)?;}
     let mut log_file = simple_log::start().unwrap();
     loop {
-        match connect_and_loop() {
+        match connect_and_loop(&config, &broadcaster) {
             Ok(val) => {
                 simple_log::ok(&mut log_file, val).unwrap();
             },
             Err(e) => {
                 println!("Error in measurement. Error message: {:?}.", e);
+                let busy = matches!(e, Tp3ErrorKind::NetBind(_));
                 simple_log::error(&mut log_file, e).unwrap();
+                if busy {thread::sleep(time::Duration::from_millis(1000));}
             },
         }
     }
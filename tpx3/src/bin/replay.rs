@@ -0,0 +1,142 @@
+//!Replays a recorded .tpx3 file as if it was the Timepix3 acquisition software. The file is sent to
+//!the packet port of the live server and paced using the pixel and TDC timestamps.
+//!
+//!Usage: `replay <file> [--speed <factor>] [--loop]`. The packet address is taken as in `NetworkConfig`
+//!(`--packet-addr`, `TP3_PACKET_ADDR` or a config file). The file is served again each time the
+//!server accepts a new connection. Pixel and TDC times are both followed in the TDC time base.
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::auxiliar::NetworkConfig;
+use timepix3::layoutlib;
+use timepix3::packetlib::{Packet, PacketEELS};
+use timepix3::timelib::TimeBase;
+use timepix3::auxiliar::value_types::*;
+use std::fs::File;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use std::convert::TryInto;
+use std::{env, thread};
+
+const BLOCK_SIZE: usize = 8_192;
+///Time base of the clock. Electron times are scaled to it, so TDCs keep their fine time.
+const CLOCK_BASE: TimeBase = TimeBase::Tdc;
+
+///Options of the command line. The other flags are those of `NetworkConfig`, which all take a value.
+struct ReplayOptions {
+    path: String,
+    speed: f64,
+    repeat: bool,
+}
+
+impl ReplayOptions {
+    fn new(args: &[String]) -> Result<Self, Tp3ErrorKind> {
+        let mut path = None;
+        let mut speed = 1.0;
+        let mut repeat = false;
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--loop" => repeat = true,
+                "--speed" => {
+                    speed = args.next()
+                        .and_then(|val| val.parse::<f64>().ok())
+                        .filter(|val| val.is_finite() && *val > 0.0)
+                        .ok_or(Tp3ErrorKind::SetNetworkConfig)?;
+                },
+                flag if flag.starts_with("--") => {args.next().ok_or(Tp3ErrorKind::SetNetworkConfig)?;},
+                value if path.is_none() => path = Some(value.to_string()),
+                _ => return Err(Tp3ErrorKind::SetNetworkConfig),
+            }
+        }
+        Ok(ReplayOptions { path: path.ok_or(Tp3ErrorKind::SetNoReadFile)?, speed, repeat })
+    }
+}
+
+///Follows the acquisition time of the file. Packets are not strictly ordered, so timestamps
+///behind the latest one do not move the clock.
+struct Clock {
+    last: Option<TIME>,
+    elapsed: TIME,
+}
+
+impl Clock {
+    fn update(&mut self, time: TIME) {
+        match self.last {
+            None => self.last = Some(time),
+            Some(last) => {
+                let overflow = CLOCK_BASE.overflow();
+                let delta = (time + overflow - last) % overflow;
+                if delta < overflow / 2 {
+                    self.elapsed += delta;
+                    self.last = Some(time);
+                }
+            },
+        }
+    }
+
+    fn scan(&mut self, block: &[u8]) {
+        for raw in block.chunks_exact(8) {
+            if raw[0..4] == *b"TPX3" {continue;}
+            let packet = PacketEELS { chip_index: 0, data: u64::from_le_bytes(raw.try_into().unwrap()) };
+            match packet.id() {
+                11 => self.update(CLOCK_BASE.electron_time(&packet)),
+                6 => self.update(CLOCK_BASE.tdc_time(&packet)),
+                _ => {},
+            }
+        }
+    }
+}
+
+///Reads until `block` is full or the file is over, so blocks stay aligned on packets.
+fn fill(file: &mut File, block: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+    let mut size = 0;
+    while size < block.len() {
+        match file.read(&mut block[size..]) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
+        }
+    }
+    Ok(size)
+}
+
+fn replay(file: &mut File, sock: &mut TcpStream, speed: f64, repeat: bool) -> Result<(), Tp3ErrorKind> {
+    let mut clock = Clock { last: None, elapsed: 0 };
+    let mut block = [0; BLOCK_SIZE];
+    let start = Instant::now();
+    loop {
+        let size = fill(file, &mut block)?;
+        if size == 0 {
+            if !repeat || clock.last.is_none() {break;}
+            println!("End of file. Replaying from the start.");
+            file.seek(SeekFrom::Start(0)).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+            clock.last = None;
+            continue;
+        }
+        clock.scan(&block[..size]);
+        let target = Duration::from_nanos((CLOCK_BASE.to_ns(clock.elapsed) / speed) as u64);
+        if let Some(wait) = target.checked_sub(start.elapsed()) {thread::sleep(wait);}
+        if sock.write_all(&block[..size]).is_err() {println!("Server disconnected."); break;}
+    }
+    println!("Replay over. Total elapsed time is: {:?}.", start.elapsed());
+    Ok(())
+}
+
+fn main() -> Result<(), Tp3ErrorKind> {
+    let args: Vec<String> = env::args().collect();
+    let config = NetworkConfig::new(&args)?;
+    layoutlib::set_layout(config.layout.clone())?;
+    let ReplayOptions { path, speed, repeat } = ReplayOptions::new(&args)?;
+
+    loop {
+        let mut file = File::open(&path).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+        let mut sock = match TcpStream::connect(config.packet_addr) {
+            Ok(sock) => sock,
+            Err(_) => {thread::sleep(Duration::from_millis(1000)); continue;},
+        };
+        println!("Replaying {} at {:?} with speed {}.", path, config.packet_addr, speed);
+        if let Err(e) = replay(&mut file, &mut sock, speed, repeat) {
+            println!("Replay interrupted. Error message: {:?}.", e);
+        }
+    }
+}
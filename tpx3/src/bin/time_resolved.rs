@@ -1,13 +1,18 @@
 use timepix3::postlib::ntime_resolved::*;
 use timepix3::tdclib::TdcType;
 use timepix3::auxiliar::{ConfigAcquisition};
+use timepix3::layoutlib;
+use timepix3::calibrationlib::{self, TimeCalibration, EnergyCalibration};
 use std::{env};
 
-fn main() -> Result<(), ErrorType> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
-    let config_set = ConfigAcquisition::new(&args);
+    let config_set = ConfigAcquisition::new(&args)?;
+    layoutlib::set_layout(config_set.layout.clone())?;
+    if let Some(path) = &config_set.time_calibration {calibrationlib::set_time_calibration(TimeCalibration::from_file(path)?)?;}
+    if let Some(dir) = &config_set.energy_calibration {calibrationlib::set_energy_calibration(EnergyCalibration::from_files(dir)?)?;}
    
-    let mut meas = TimeSpectralSpatial::new(&config_set)?;
+    let mut meas = TimeSpectralSpatial::new(&config_set).map_err(|e| format!("{:?}", e))?;
     analyze_data(&config_set.file(), &mut meas);
 
     Ok(())
@@ -0,0 +1,21 @@
+use timepix3::postlib::tot_spectra::*;
+use timepix3::calibrationlib::TOT_RANGE;
+use timepix3::layoutlib::{self, DetectorLayout};
+use std::env;
+
+///Builds the ToT histogram of each pixel of a file, to fit the energy calibration. Arguments are
+///the file, the output file and, optionally, the number of ToT bins (`TOT_RANGE` by default). The detector
+///layout is given by `--layout`.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    let mut args: Vec<String> = env::args().collect();
+    layoutlib::set_layout(DetectorLayout::take_arg(&mut args)?)?;
+    if args.len() != 3 && args.len() != 4 {
+        panic!("One must provide the file, the output file and optionally the number of bins.");
+    }
+    let bins = args.get(3).map_or(Ok(TOT_RANGE), |bins| bins.parse::<usize>())?;
+    let histograms = build_tot_histograms(&args[1], bins.max(1))?;
+    histograms.output(&args[2]);
+
+    Ok(())
+}
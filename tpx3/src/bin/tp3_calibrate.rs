@@ -0,0 +1,29 @@
+use timepix3::postlib::coincidence::*;
+use timepix3::auxiliar::ConfigAcquisition;
+use timepix3::layoutlib;
+use timepix3::calibrationlib::{self, TimeCalibration, EnergyCalibration};
+use std::env;
+
+///Derives the time calibration from a coincidence dataset. Arguments are the ones of `tp3_coin`,
+///followed by the output file. A calibration given as input is refined.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    let mut args: Vec<String> = env::args().collect();
+    let output = match args.pop() {
+        Some(output) if args.len() >= 6 => output,
+        _ => panic!("One must provide the arguments of the coincidence search followed by the output file."),
+    };
+    let config_set = ConfigAcquisition::new(&args)?;
+    layoutlib::set_layout(config_set.layout.clone())?;
+    if let Some(path) = &config_set.time_calibration {calibrationlib::set_time_calibration(TimeCalibration::from_file(path)?)?;}
+    if let Some(dir) = &config_set.energy_calibration {calibrationlib::set_energy_calibration(EnergyCalibration::from_files(dir)?)?;}
+    let mut coinc_data = ElectronData::new(&config_set);
+    coinc_data.set_coincidence_window(104, 400);
+    search_coincidence(&config_set.file(), &mut coinc_data)?;
+
+    let calibration = coinc_data.time_calibration();
+    calibration.save(&output).expect("Could not save the time calibration.");
+    println!("Time calibration saved to {}. Coincidence delay is {} ns and width is {} ns.", output, calibration.coincidence_delay, calibration.coincidence_width);
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//!`broadcastlib` fans out the frames sent to the client. Every subscriber is served by its own thread
+//!through a bounded queue. Extra consumers (a monitoring dashboard, a frame recorder) lose their
+//!oldest frames when they fall behind, so they never stall the packet ingestion. The client that
+//!started the measurement is waited for a while before its oldest frame is dropped as well.
+use crate::errorlib::Tp3ErrorKind;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{TcpListener, SocketAddr};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+///Frames queued for the client that started the measurement. Above it, the measurement waits up to
+///`PRIMARY_PATIENCE` before the oldest frame is dropped.
+const PRIMARY_DEPTH: usize = 64;
+const PRIMARY_PATIENCE: Duration = Duration::from_millis(200);
+///Frames queued for any other subscriber. Above it, the oldest frame is dropped.
+const SUBSCRIBER_DEPTH: usize = 4;
+
+type Frame = Arc<Vec<u8>>;
+
+#[derive(Default)]
+struct QueueState {
+    frames: VecDeque<Frame>,
+    dropped: usize,
+    finished: bool,
+    dead: bool,
+}
+
+struct FrameQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    space: Condvar,
+    depth: usize,
+    ///How long a push waits for room before dropping the oldest frame.
+    patience: Duration,
+}
+
+impl FrameQueue {
+    fn new(depth: usize, patience: Duration) -> Self {
+        FrameQueue {
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            depth,
+            patience,
+        }
+    }
+
+    ///Queues a frame. Returns false if the consumer is gone.
+    fn push(&self, frame: Frame) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let deadline = Instant::now() + self.patience;
+        while state.frames.len() >= self.depth && !state.dead {
+            let now = Instant::now();
+            if now >= deadline {break;}
+            state = self.space.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        if state.dead {return false;}
+        if state.frames.len() >= self.depth {
+            state.frames.pop_front();
+            state.dropped += 1;
+        }
+        state.frames.push_back(frame);
+        self.ready.notify_one();
+        true
+    }
+
+    ///No more frames will be pushed. The consumer thread ends once the queue is empty.
+    fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.ready.notify_one();
+    }
+
+    fn is_dead(&self) -> bool {
+        self.state.lock().unwrap().dead
+    }
+
+    fn serve<W: Write>(&self, mut writer: W) {
+        loop {
+            let frame = {
+                let mut state = self.state.lock().unwrap();
+                while state.frames.is_empty() && !state.finished {
+                    state = self.ready.wait(state).unwrap();
+                }
+                match state.frames.pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                }
+            };
+            self.space.notify_one();
+            if writer.write_all(&frame).and_then(|_| writer.flush()).is_err() {
+                let mut state = self.state.lock().unwrap();
+                state.dead = true;
+                state.frames.clear();
+                self.space.notify_one();
+                break;
+            }
+        }
+        let dropped = self.state.lock().unwrap().dropped;
+        if dropped > 0 {println!("Consumer finished. {} frames were dropped.", dropped);}
+    }
+}
+
+fn spawn_queue<W: 'static + Send + Write>(writer: W, depth: usize, patience: Duration) -> Arc<FrameQueue> {
+    let queue = Arc::new(FrameQueue::new(depth, patience));
+    let thread_queue = Arc::clone(&queue);
+    thread::spawn(move || thread_queue.serve(writer));
+    queue
+}
+
+///Subscribers list. It outlives the measurements, so consumers stay connected between them. Clones
+///share the same list.
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    subscribers: Arc<Mutex<Vec<Arc<FrameQueue>>>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Broadcaster::default()
+    }
+
+    ///Adds a consumer receiving every frame of every measurement. It is removed once a write to it fails.
+    pub fn subscribe<W: 'static + Send + Write>(&self, writer: W) {
+        let queue = spawn_queue(writer, SUBSCRIBER_DEPTH, Duration::ZERO);
+        self.subscribers.lock().unwrap().push(queue);
+    }
+
+    ///Accepts consumers on `addr` in a background thread.
+    pub fn listen(&self, addr: SocketAddr) -> Result<(), Tp3ErrorKind> {
+        let listener = TcpListener::bind(addr).map_err(|_| Tp3ErrorKind::NetBind(addr))?;
+        println!("Broadcast Tcp socket connected at: {:?}", listener);
+        let broadcaster = self.clone();
+        thread::spawn(move || {
+            for sock in listener.incoming().flatten() {
+                println!("Broadcast subscriber connected at {:?}.", sock.peer_addr());
+                broadcaster.subscribe(sock);
+            }
+        });
+        Ok(())
+    }
+
+    ///Returns the `Write` handed to a measurement. `primary` is the client that started it.
+    pub fn frame_writer<W: 'static + Send + Write>(&self, primary: W) -> FrameWriter {
+        FrameWriter {
+            broadcaster: self.clone(),
+            primary: spawn_queue(primary, PRIMARY_DEPTH, PRIMARY_PATIENCE),
+            frame: Vec::new(),
+        }
+    }
+
+    ///Sends `data` as one frame to every subscriber.
+    pub fn send(&self, data: Vec<u8>) {
+        self.publish(&Arc::new(data));
+    }
+
+    fn publish(&self, frame: &Frame) {
+        self.subscribers.lock().unwrap().retain(|queue| queue.push(Arc::clone(frame)));
+    }
+}
+
+///`Write` end of a `Broadcaster`. Writes are gathered until `flush`, which publishes them as one frame.
+///Writing fails once the primary client is gone, as it did with a bare socket.
+pub struct FrameWriter {
+    broadcaster: Broadcaster,
+    primary: Arc<FrameQueue>,
+    frame: Vec<u8>,
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.primary.is_dead() {return Err(io::Error::from(io::ErrorKind::BrokenPipe));}
+        self.frame.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.frame.is_empty() {return Ok(());}
+        let capacity = self.frame.len();
+        let frame = Arc::new(std::mem::replace(&mut self.frame, Vec::with_capacity(capacity)));
+        if !self.primary.push(Arc::clone(&frame)) {return Err(io::Error::from(io::ErrorKind::BrokenPipe));}
+        self.broadcaster.publish(&frame);
+        Ok(())
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        self.primary.finish();
+    }
+}
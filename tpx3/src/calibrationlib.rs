@@ -0,0 +1,160 @@
+//!`calibrationlib` holds the calibrations of the detector. The timing calibration is a ToA offset for
+//!each pixel and a time-walk curve as a function of the ToT. It is applied by
+//!`packetlib::CalibratedPacketEELS` and derived from coincidence data in `postlib`. The energy
+//!calibration converts the ToT of each pixel to the deposited energy. Calibrations are loaded once
+//!per process.
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::value_types::*;
+use crate::layoutlib::layout;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::OnceLock;
+
+///Number of ToT values (10 bits).
+pub const TOT_RANGE: usize = 1024;
+
+static TIME_CALIBRATION: OnceLock<TimeCalibration> = OnceLock::new();
+static ENERGY_CALIBRATION: OnceLock<EnergyCalibration> = OnceLock::new();
+
+///Sets the calibration of the process. Fails if a calibration was already set.
+pub fn set_time_calibration(calibration: TimeCalibration) -> Result<(), Tp3ErrorKind> {
+    TIME_CALIBRATION.set(calibration).map_err(|_| Tp3ErrorKind::CalibrationBadFile)
+}
+
+///Calibration of the process, if any.
+#[inline]
+pub fn time_calibration() -> Option<&'static TimeCalibration> {
+    TIME_CALIBRATION.get()
+}
+
+///Timing calibration. Times are in ns and are subtracted from the electron time. The file is the JSON
+///serialization of this struct.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeCalibration {
+    pub width: POSITION,
+    pub height: POSITION,
+    ///Offset of each pixel, row by row (`x + width * y`).
+    pub offsets: Vec<f32>,
+    ///Delay of a hit as a function of its ToT.
+    pub time_walk: Vec<f32>,
+    ///Photon time minus electron time once corrected, for the coincidence search.
+    pub coincidence_delay: f32,
+    ///Half width of the coincidence window once corrected.
+    pub coincidence_width: f32,
+}
+
+impl TimeCalibration {
+    ///A calibration that changes nothing, for the current detector layout.
+    pub fn empty() -> Self {
+        let (width, height) = (layout().width(), layout().height());
+        TimeCalibration {
+            width,
+            height,
+            offsets: vec![0.0; (width * height) as usize],
+            time_walk: vec![0.0; TOT_RANGE],
+            coincidence_delay: 0.0,
+            coincidence_width: 0.0,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Tp3ErrorKind> {
+        let file = File::open(path).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+        let calibration: TimeCalibration = serde_json::from_reader(file).map_err(|_| Tp3ErrorKind::CalibrationBadFile)?;
+        if calibration.offsets.len() != (calibration.width * calibration.height) as usize || calibration.time_walk.len() != TOT_RANGE {
+            return Err(Tp3ErrorKind::CalibrationBadFile);
+        }
+        if (calibration.width, calibration.height) != (layout().width(), layout().height()) {
+            return Err(Tp3ErrorKind::CalibrationBadLayout);
+        }
+        Ok(calibration)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Tp3ErrorKind> {
+        let file = File::create(path).map_err(|_| Tp3ErrorKind::SetNoWriteFile)?;
+        serde_json::to_writer(file, self).map_err(|_| Tp3ErrorKind::SetNoWriteFile)
+    }
+
+    ///Correction of a hit, in ns.
+    #[inline]
+    pub fn correction(&self, x: POSITION, y: POSITION, tot: u16) -> f32 {
+        let offset = self.offsets.get((x + self.width * y) as usize).copied().unwrap_or(0.0);
+        let walk = self.time_walk.get(tot as usize).copied().unwrap_or(0.0);
+        offset + walk
+    }
+}
+
+///Sets the energy calibration of the process. Fails if a calibration was already set.
+pub fn set_energy_calibration(calibration: EnergyCalibration) -> Result<(), Tp3ErrorKind> {
+    ENERGY_CALIBRATION.set(calibration).map_err(|_| Tp3ErrorKind::CalibrationBadFile)
+}
+
+///Energy calibration of the process, if any.
+#[inline]
+pub fn energy_calibration() -> Option<&'static EnergyCalibration> {
+    ENERGY_CALIBRATION.get()
+}
+
+///ToT to energy calibration. Each pixel follows the surrogate function `ToT = a * E + b - c / (E - t)`,
+///with the energy `E` in keV.
+#[derive(Clone, Debug)]
+pub struct EnergyCalibration {
+    width: POSITION,
+    a: Vec<f32>,
+    b: Vec<f32>,
+    c: Vec<f32>,
+    t: Vec<f32>,
+}
+
+impl EnergyCalibration {
+    ///Reads the parameters from `a.txt`, `b.txt`, `c.txt` and `t.txt` in `dir`. Each file holds one
+    ///row of whitespace-separated values per row of the detector.
+    pub fn from_files(dir: &str) -> Result<Self, Tp3ErrorKind> {
+        let (width, height) = (layout().width(), layout().height());
+        let read = |name: &str| -> Result<Vec<f32>, Tp3ErrorKind> {
+            let text = fs::read_to_string(Path::new(dir).join(name)).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+            let values = text.split_whitespace()
+                .map(|value| value.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| Tp3ErrorKind::CalibrationBadFile)?;
+            if values.len() != (width * height) as usize {return Err(Tp3ErrorKind::CalibrationBadLayout);}
+            Ok(values)
+        };
+        Ok(EnergyCalibration {
+            width,
+            a: read("a.txt")?,
+            b: read("b.txt")?,
+            c: read("c.txt")?,
+            t: read("t.txt")?,
+        })
+    }
+
+    ///Energy of a hit, in keV. None if the pixel is not calibrated.
+    #[inline]
+    pub fn energy(&self, x: POSITION, y: POSITION, tot: u16) -> Option<f32> {
+        let index = (x + self.width * y) as usize;
+        let (a, b, c, t) = (*self.a.get(index)?, self.b[index], self.c[index], self.t[index]);
+        if a <= 0.0 {return None;}
+        let tot = tot as f32;
+        let term = b + a * t - tot;
+        Some((a * t + tot - b + (term * term + 4.0 * a * c).sqrt()) / (2.0 * a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn energy_inverts_the_surrogate_function() {
+        let (a, b, c, t) = (2.0, 30.0, 200.0, 3.0);
+        let calibration = EnergyCalibration { width: 1, a: vec![a], b: vec![b], c: vec![c], t: vec![t] };
+        for energy in (10..480).map(|energy| energy as f32) {
+            let tot = (a * energy + b - c / (energy - t)).round();
+            let found = calibration.energy(0, 0, tot as u16).unwrap();
+            //The ToT is an integer, so the energy is known to half a ToT step.
+            assert!((found - energy).abs() <= 0.5 / a, "{} keV found for {} keV.", found, energy);
+        }
+        assert_eq!(calibration.energy(1, 0, 100), None);
+    }
+}
@@ -1,10 +1,12 @@
 //!`clusterlib` is a collection of tools to identify and manipulate TPX3 cluster.
 
 pub mod cluster {
-    use crate::spimlib::{SPIM_PIXELS, VIDEO_TIME};
-    use crate::packetlib::{Packet, PacketEELS as Pack};
+    use crate::spimlib::{spim_pixels, VIDEO_TIME};
+    use crate::packetlib::Packet;
+    use crate::timelib::TimeBase;
     use crate::spimlib;
     use crate::tdclib::PeriodicTdcRef;
+    use crate::calibrationlib::energy_calibration;
     use std::fs::OpenOptions;
     use std::io::Write;
     use rayon::prelude::*;
@@ -75,26 +77,6 @@ pub mod cluster {
             self.data = nelist;
         }
 
-        pub fn check_if_overflow(&self) -> bool {
-            let first = self.data.get(0).expect("No first value detected.");
-            let last = self.data.iter().last().expect("No last value detected.");
-            first.time() > last.time()
-        }
-
-        pub fn correct_electron_time(&mut self, overflow: COUNTER) -> bool {
-            if self.check_if_overflow() {
-                let overflow_index = self.data.iter().
-                    enumerate().
-                    min_by_key(|x| x.1.time()).unwrap().0;
-                self.data[0..overflow_index].iter_mut().for_each(|se| se.correct_time_overflow(overflow));
-                self.data[overflow_index..].iter_mut().for_each(|se| se.correct_time_overflow(overflow+1));
-                true
-            } else {
-                self.data.iter_mut().for_each(|se| se.correct_time_overflow(overflow));
-                false
-            }
-        }
-
         pub fn sort(&mut self) {
             self.data.par_sort_unstable_by(|a, b| (a.data).partial_cmp(&b.data).unwrap());
         }
@@ -122,6 +104,12 @@ pub mod cluster {
             self.data.clear();
         }
 
+        ///Keeps the electrons (or clusters) whose energy, in keV, is in [`min`, `max`]. Electrons without
+        ///a calibrated energy are removed.
+        pub fn retain_energy(&mut self, min: f32, max: f32) {
+            self.data.retain(|se| se.energy().is_some_and(|energy| energy >= min && energy <= max));
+        }
+
         pub fn output_data(&self, filename: String, slice: COUNTER) {
             let mut tfile = OpenOptions::new()
                 .append(true)
@@ -194,10 +182,10 @@ pub mod cluster {
         */
     }
 
-    ///ToA, X, Y, Spim dT, Spim Slice, ToT, Cluster Size
+    ///ToA, X, Y, Spim dT, Spim Slice, ToT, Cluster Size, Energy (keV, if calibrated)
     #[derive(Copy, Clone, Debug)]
     pub struct SingleElectron {
-        data: (TIME, POSITION, POSITION, TIME, COUNTER, u16, usize),
+        data: (TIME, POSITION, POSITION, TIME, COUNTER, u16, usize, Option<f32>),
     }
 
     impl ToString for SingleElectron {
@@ -223,25 +211,24 @@ pub mod cluster {
     }
 
     impl SingleElectron {
-        pub fn new<T: Packet>(pack: &T, begin_frame: Option<PeriodicTdcRef>) -> Self {
+        ///`time` is the absolute electron time, as given by `timelib::Timeline`.
+        pub fn new<T: Packet>(pack: &T, time: TIME, begin_frame: Option<PeriodicTdcRef>) -> Self {
+            let energy = energy_calibration().and_then(|calibration| calibration.energy(pack.x(), pack.y(), pack.tot()));
             match begin_frame {
                 Some(spim_tdc) => {
-                    let ele_time = spimlib::correct_or_not_etime(pack.electron_time(), &spim_tdc);
+                    let ele_time = spimlib::correct_or_not_etime(time, &spim_tdc);
                     SingleElectron {
-                        data: (pack.electron_time(), pack.x(), pack.y(), ele_time-spim_tdc.begin_frame-VIDEO_TIME, spim_tdc.frame(), pack.tot(), 1),
+                        data: (time, pack.x(), pack.y(), ele_time-spim_tdc.begin_frame-VIDEO_TIME, spim_tdc.frame(), pack.tot(), 1, energy),
                     }
                 },
                 None => {
                     SingleElectron {
-                        data: (pack.electron_time(), pack.x(), pack.y(), 0, 0, pack.tot(), 1),
+                        data: (time, pack.x(), pack.y(), 0, 0, pack.tot(), 1, energy),
                     }
                 },
             }
         }
 
-        pub fn correct_time_overflow(&mut self, overflow: COUNTER) {
-            self.data.0 += overflow as TIME * Pack::electron_overflow();
-        }
         pub fn x(&self) -> POSITION {
             self.data.1
         }
@@ -258,13 +245,14 @@ pub mod cluster {
             self.data.3
         }
         pub fn image_index(&self) -> POSITION {
-            self.data.1 + SPIM_PIXELS*self.data.2
+            self.data.1 + spim_pixels()*self.data.2
         }
         pub fn relative_time(&self, reference_time: TIME) -> i64 {
             self.data.0 as i64 - reference_time as i64
         }
+        ///Time from `reference_time`, given in the TDC time base.
         pub fn relative_time_from_abs_tdc(&self, reference_time: TIME) -> i64 {
-            (self.data.0*6) as i64 - reference_time as i64
+            TimeBase::Tdc.convert(self.data.0, TimeBase::Electron) as i64 - reference_time as i64
         }
         pub fn spim_slice(&self) -> COUNTER {
             self.data.4
@@ -272,6 +260,10 @@ pub mod cluster {
         pub fn cluster_size(&self) -> usize {
             self.data.6
         }
+        ///Deposited energy, in keV. None without an energy calibration.
+        pub fn energy(&self) -> Option<f32> {
+            self.data.7
+        }
 
         fn is_new_cluster(&self, s: &SingleElectron) -> bool {
             if self.time() > s.time() + CLUSTER_DET || (self.x() as isize - s.x() as isize).abs() > CLUSTER_SPATIAL || (self.y() as isize - s.y() as isize).abs() > CLUSTER_SPATIAL {
@@ -313,10 +305,14 @@ pub mod cluster {
                 map(|se| se.tot() as usize).
                 sum::<usize>() as u16;
 
+            let energy_sum: Option<f32> = cluster.iter().
+                map(|se| se.energy()).
+                sum();
+
             let cluster_size: usize = cluster_size;
 
             Some(SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             })
         }
         
@@ -360,10 +356,15 @@ pub mod cluster {
                 map(|se| se.tot() as usize).
                 sum::<usize>() as u16;
 
+            let energy_sum: Option<f32> = cluster.iter().
+                filter(|se| se.tot() > tot_threshold).
+                map(|se| se.energy()).
+                sum();
+
             let cluster_size: usize = cluster_size;
 
             Some(SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             })
         }
 
@@ -377,10 +378,11 @@ pub mod cluster {
             let time_dif: TIME = cluster.iter().map(|se| se.frame_dt()).next().unwrap();
             let slice: COUNTER = cluster.iter().map(|se| se.spim_slice()).next().unwrap();
             let tot_sum: u16 = cluster.iter().map(|se| se.tot() as usize).sum::<usize>() as u16;
+            let energy_sum: Option<f32> = cluster.iter().map(|se| se.energy()).sum();
             let cluster_size: usize = cluster_size;
 
             Some(SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             })
         }
 
@@ -431,10 +433,14 @@ pub mod cluster {
                 map(|se| se.tot() as usize).
                 sum::<usize>() as u16;
             
+            let energy_sum: Option<f32> = cluster.iter().
+                map(|se| se.energy()).
+                sum();
+
             let cluster_size: usize = cluster_size;
 
             Some(SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             })
         }
     }
@@ -471,10 +477,14 @@ pub mod cluster {
                 map(|se| se.tot() as usize).
                 sum::<usize>() as u16;
 
+            let energy_sum: Option<f32> = cluster.iter().
+                map(|se| se.energy()).
+                sum();
+
             let cluster_size: usize = cluster_size;
 
             Some(SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             })
         }
     }
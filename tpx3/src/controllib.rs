@@ -0,0 +1,242 @@
+//!`controllib` lets the client stop, pause or resume a live measurement. Commands are framed messages
+//!of type `MESSAGE_CONTROL` sent on the client socket, whose payload is `{"command": "stop"}`,
+//!`{"command": "pause"}` or `{"command": "resume"}`.
+//!
+//!Stopping ends the measurement as if the Timepix3 stream was over. While paused, packets are still
+//!decoded (so TDC references stay locked) but frames are sent neither to the client nor to the
+//!broadcast subscribers. The pause is checked once per frame, so a frame is always sent or dropped whole. Once the measurement
+//!is over, a summary is sent as a `MESSAGE_SUMMARY` message to clients that used the control channel.
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::{misc::TimepixRead, read_message, write_message, MESSAGE_CONTROL, MESSAGE_SUMMARY};
+use crate::tdclib::TdcType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const STOPPED: u8 = 2;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Command {
+    Stop,
+    Pause,
+    Resume,
+}
+
+#[derive(Deserialize)]
+struct ControlMessage {
+    command: Command,
+}
+
+///Final report of a measurement.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub frames: u64,
+    pub electrons: u64,
+    ///Number of TDC events received, per input line and edge.
+    pub tdc_counters: BTreeMap<String, u64>,
+    pub elapsed: f64,
+    pub stopped: bool,
+}
+
+struct ControlState {
+    state: AtomicU8,
+    framed: AtomicBool,
+    frames: AtomicU64,
+    electrons: AtomicU64,
+    tdcs: [AtomicU64; 16],
+    start: Instant,
+    sockets: Mutex<Vec<TcpStream>>,
+}
+
+///Handle shared by the control thread and the measurement streams.
+#[derive(Clone)]
+pub struct AcquisitionControl {
+    inner: Arc<ControlState>,
+}
+
+impl AcquisitionControl {
+    ///Listens for commands on `ns`. `pack` is shut down on stop, so a measurement waiting for packets
+    ///ends as well.
+    pub fn listen(ns: &TcpStream, pack: Option<&TcpStream>) -> Result<Self, Tp3ErrorKind> {
+        let mut sockets = vec![ns.try_clone().map_err(|_| Tp3ErrorKind::NetAccept)?];
+        if let Some(pack) = pack {
+            sockets.push(pack.try_clone().map_err(|_| Tp3ErrorKind::NetAccept)?);
+        }
+        let control = AcquisitionControl {
+            inner: Arc::new(ControlState {
+                state: AtomicU8::new(RUNNING),
+                framed: AtomicBool::new(false),
+                frames: AtomicU64::new(0),
+                electrons: AtomicU64::new(0),
+                tdcs: Default::default(),
+                start: Instant::now(),
+                sockets: Mutex::new(sockets),
+            }),
+        };
+
+        let mut ns = ns.try_clone().map_err(|_| Tp3ErrorKind::NetAccept)?;
+        let thread_control = control.clone();
+        thread::spawn(move || {
+            loop {
+                match read_message(&mut ns) {
+                    Ok((MESSAGE_CONTROL, payload)) => {
+                        match serde_json::from_slice::<ControlMessage>(&payload) {
+                            Ok(message) => {
+                                thread_control.inner.framed.store(true, Ordering::Relaxed);
+                                thread_control.apply(message.command);
+                            },
+                            Err(_) => println!("Unknown control message: {:?}.", String::from_utf8_lossy(&payload)),
+                        }
+                    },
+                    Ok((version, _)) => println!("Unexpected message of type {} on the control channel.", version),
+                    Err(Tp3ErrorKind::SetNoSettings) => {
+                        //Client is gone or the measurement is over.
+                        thread_control.apply(Command::Stop);
+                        break;
+                    },
+                    Err(_) => {
+                        println!("Unexpected data on the control channel. Commands are no longer read.");
+                        break;
+                    },
+                }
+            }
+        });
+        Ok(control)
+    }
+
+    pub fn apply(&self, command: Command) {
+        let state = match command {
+            Command::Stop => STOPPED,
+            Command::Pause => PAUSED,
+            Command::Resume => RUNNING,
+        };
+        //A stopped measurement can not be resumed.
+        let _ = self.inner.state.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            match current {
+                STOPPED => None,
+                _ => Some(state),
+            }
+        });
+        if state == STOPPED {
+            if let Some(pack) = self.inner.sockets.lock().unwrap().get(1) {
+                let _ = pack.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    fn state(&self) -> u8 {
+        self.inner.state.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> Summary {
+        let tdc_counters = self.inner.tdcs.iter()
+            .enumerate()
+            .filter_map(|(value, count)| {
+                TdcType::associate_value_to_enum(value as u8).map(|tdc| (format!("{:?}", tdc), count.load(Ordering::Relaxed)))
+            })
+            .collect();
+        Summary {
+            frames: self.inner.frames.load(Ordering::Relaxed),
+            electrons: self.inner.electrons.load(Ordering::Relaxed),
+            tdc_counters,
+            elapsed: self.inner.start.elapsed().as_secs_f64(),
+            stopped: self.state() == STOPPED,
+        }
+    }
+
+    ///Wraps the Timepix3 stream. Reading fails once the measurement is stopped.
+    pub fn reader<R: TimepixRead>(&self, inner: R) -> ControlledRead<R> {
+        ControlledRead { inner, control: self.clone() }
+    }
+
+    ///Wraps the client stream. Frames are discarded while paused.
+    pub fn writer<W: Write>(&self, inner: W) -> ControlledWrite<W> {
+        ControlledWrite { inner, control: self.clone(), paused: false, frame_open: false }
+    }
+
+    fn count(&self, data: &[u8]) {
+        let mut electrons = 0;
+        for raw in data.chunks_exact(8) {
+            if raw[0..4] == *b"TPX3" {continue;}
+            match raw[7] >> 4 {
+                11 => electrons += 1,
+                6 => {self.inner.tdcs[(raw[7] & 15) as usize].fetch_add(1, Ordering::Relaxed);},
+                _ => {},
+            }
+        }
+        self.inner.electrons.fetch_add(electrons, Ordering::Relaxed);
+    }
+}
+
+pub struct ControlledRead<R> {
+    inner: R,
+    control: AcquisitionControl,
+}
+
+impl<R: TimepixRead> Read for ControlledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: TimepixRead> TimepixRead for ControlledRead<R> {
+    fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+        if self.control.state() == STOPPED {return Err(Tp3ErrorKind::TimepixReadOver);}
+        let size = self.inner.read_timepix(buf)?;
+        self.control.count(&buf[..size]);
+        Ok(size)
+    }
+}
+
+pub struct ControlledWrite<W: Write> {
+    inner: W,
+    control: AcquisitionControl,
+    paused: bool,
+    ///A frame is being written. It ends at `flush`.
+    frame_open: bool,
+}
+
+impl<W: Write> Write for ControlledWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.frame_open {
+            self.paused = self.control.state() == PAUSED;
+            self.frame_open = true;
+        }
+        match self.paused {
+            true => Ok(buf.len()),
+            false => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let frame_open = std::mem::replace(&mut self.frame_open, false);
+        if self.paused {return Ok(());}
+        if frame_open {self.control.inner.frames.fetch_add(1, Ordering::Relaxed);}
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ControlledWrite<W> {
+    fn drop(&mut self) {
+        let summary = self.control.summary();
+        println!("Measurement summary: {:?}.", summary);
+        if self.control.inner.framed.load(Ordering::Relaxed) {
+            if let Ok(payload) = serde_json::to_vec(&summary) {
+                let _ = write_message(&mut self.inner, MESSAGE_SUMMARY, &payload);
+            }
+        }
+        //Frames still queued for the client are sent before the socket is closed, so only the
+        //control channel is shut down here.
+        if let Some(ns) = self.control.inner.sockets.lock().unwrap().first() {
+            let _ = ns.shutdown(Shutdown::Read);
+        }
+    }
+}
@@ -1,4 +1,6 @@
 //!`errorlib` is a simply enumeration to control error handling and logging.
+use crate::tdclib::TdcType;
+
 #[derive(Debug)]
 pub enum Tp3ErrorKind {
     SetBin,
@@ -9,17 +11,46 @@ pub enum Tp3ErrorKind {
     SetYSize,
     SetNoReadFile,
     SetNoWriteFile,
+    SetNoSettings,
+    SetBadSettings,
+    SetVersion(u8),
+    SetNetworkConfig,
+    SetDetectorLayout,
+    ///A command-line argument is missing or cannot be parsed.
+    SetArgument,
+
+    NetBind(std::net::SocketAddr),
+    NetAccept,
+    NetConnect(std::net::SocketAddr),
 
-    TdcNoReceived,
+    ///The reference TDC `missing` was found `found` times before the timeout or the end of the
+    ///stream. `seen` counts the TDCs received, of any line and edge.
+    TdcNoReceived { missing: TdcType, found: usize, seen: Vec<(TdcType, usize)> },
+    ///More data than `TdcDiscovery` can keep was read before the TDC references were found.
+    TdcDiscoveryFull,
     TdcBadPeriod,
     TdcBadHighTime,
     TdcNotAscendingOrder,
     TdcZeroBytes,
 
+    CalibrationBadFile,
+    CalibrationBadLayout,
+
+    SimulationBadSettings,
+
     MiscModeNotImplemented(u8),
+    MiscHeaderSerialization,
 
     TimepixReadLoop,
     TimepixReadOver,
 
     IsiBoxAttempt(u8),
 }
+
+impl std::fmt::Display for Tp3ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Tp3ErrorKind {}
@@ -0,0 +1,205 @@
+//!`layoutlib` describes how the chips are placed on the detector. The layout is set once per process,
+//!before any packet is decoded, and is used by `Packet::x` and `Packet::y`. The default layout is the
+//!1x4 EELS camera.
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::value_types::*;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::sync::OnceLock;
+
+///Side of a chip, in pixels.
+pub const CHIP_SIZE: POSITION = 256;
+
+static LAYOUT: OnceLock<DetectorLayout> = OnceLock::new();
+
+///Sets the layout of the process. Fails if a layout was already set or used.
+pub fn set_layout(layout: DetectorLayout) -> Result<(), Tp3ErrorKind> {
+    LAYOUT.set(layout).map_err(|_| Tp3ErrorKind::SetDetectorLayout)
+}
+
+///Layout of the process.
+#[inline]
+pub fn layout() -> &'static DetectorLayout {
+    LAYOUT.get_or_init(DetectorLayout::eels)
+}
+
+///Placement of a chip. `column` and `row` are given in chips. Pixel coordinates are transposed, then
+///flipped, before being moved to their place.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ChipPlacement {
+    pub column: POSITION,
+    pub row: POSITION,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub transpose: bool,
+}
+
+impl ChipPlacement {
+    const fn new(column: POSITION, row: POSITION, flip_x: bool, flip_y: bool) -> Self {
+        ChipPlacement { column, row, flip_x, flip_y, transpose: false }
+    }
+}
+
+///Chips of the detector, by chip index.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "LayoutSpec")]
+pub struct DetectorLayout {
+    chips: Vec<ChipPlacement>,
+    gap: POSITION,
+    width: POSITION,
+    height: POSITION,
+}
+
+///A layout is given either by the name of a preset or by its chips.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayoutSpec {
+    Preset(String),
+    Chips {
+        chips: Vec<ChipPlacement>,
+        #[serde(default)]
+        gap: POSITION,
+    },
+}
+
+impl TryFrom<LayoutSpec> for DetectorLayout {
+    type Error = String;
+    fn try_from(spec: LayoutSpec) -> Result<Self, String> {
+        match spec {
+            LayoutSpec::Preset(name) => DetectorLayout::preset(&name).ok_or(format!("Unknown detector layout {}.", name)),
+            LayoutSpec::Chips { chips, gap } => DetectorLayout::new(chips, gap).map_err(|e| format!("{:?}", e)),
+        }
+    }
+}
+
+impl Default for DetectorLayout {
+    fn default() -> Self {
+        DetectorLayout::eels()
+    }
+}
+
+impl DetectorLayout {
+    ///`gap` is the number of empty pixels between neighbouring chips. Two chips can not share a place.
+    pub fn new(chips: Vec<ChipPlacement>, gap: POSITION) -> Result<Self, Tp3ErrorKind> {
+        if chips.is_empty() || chips.len() > u8::MAX as usize {return Err(Tp3ErrorKind::SetDetectorLayout);}
+        for (index, chip) in chips.iter().enumerate() {
+            if chips[..index].iter().any(|other| (other.column, other.row) == (chip.column, chip.row)) {
+                return Err(Tp3ErrorKind::SetDetectorLayout);
+            }
+        }
+        let extent = |tiles: POSITION| tiles * CHIP_SIZE + (tiles - 1) * gap;
+        let width = extent(chips.iter().map(|chip| chip.column).max().unwrap() + 1);
+        let height = extent(chips.iter().map(|chip| chip.row).max().unwrap() + 1);
+        Ok(DetectorLayout { chips, gap, width, height })
+    }
+
+    ///Four chips side by side, as in the EELS camera.
+    pub fn eels() -> Self {
+        let chips = vec![
+            ChipPlacement::new(0, 0, true, false),
+            ChipPlacement::new(3, 0, true, false),
+            ChipPlacement::new(2, 0, true, false),
+            ChipPlacement::new(1, 0, true, false),
+        ];
+        DetectorLayout::new(chips, 0).unwrap()
+    }
+
+    ///Four chips in a 2x2 square, as in the diffraction camera.
+    pub fn quad() -> Self {
+        let chips = vec![
+            ChipPlacement::new(0, 0, true, false),
+            ChipPlacement::new(0, 1, false, true),
+            ChipPlacement::new(1, 1, false, true),
+            ChipPlacement::new(1, 0, true, false),
+        ];
+        DetectorLayout::new(chips, 0).unwrap()
+    }
+
+    ///A single chip.
+    pub fn single() -> Self {
+        DetectorLayout::new(vec![ChipPlacement::new(0, 0, true, false)], 0).unwrap()
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "eels" => Some(DetectorLayout::eels()),
+            "quad" => Some(DetectorLayout::quad()),
+            "single" => Some(DetectorLayout::single()),
+            _ => None,
+        }
+    }
+
+    ///Reads a layout from a preset name or from a JSON file.
+    pub fn from_arg(value: &str) -> Result<Self, Tp3ErrorKind> {
+        if let Some(layout) = DetectorLayout::preset(value) {return Ok(layout);}
+        let file = File::open(value).map_err(|_| Tp3ErrorKind::SetNoReadFile)?;
+        serde_json::from_reader(file).map_err(|_| Tp3ErrorKind::SetDetectorLayout)
+    }
+
+    ///Layout given by `--layout <value>` in `args`, else by `TP3_LAYOUT`, else the default one. The flag
+    ///and its value are removed from `args`, so the other arguments can be read by position.
+    pub fn take_arg(args: &mut Vec<String>) -> Result<Self, Tp3ErrorKind> {
+        let value = match args.iter().position(|arg| arg == "--layout") {
+            Some(index) => {
+                let value = args.get(index+1).cloned().ok_or(Tp3ErrorKind::SetArgument)?;
+                args.drain(index..index+2);
+                Some(value)
+            },
+            None => std::env::var("TP3_LAYOUT").ok(),
+        };
+        match value {
+            Some(value) => DetectorLayout::from_arg(&value),
+            None => Ok(DetectorLayout::default()),
+        }
+    }
+
+    pub fn chips(&self) -> &[ChipPlacement] {
+        &self.chips
+    }
+
+    pub fn gap(&self) -> POSITION {
+        self.gap
+    }
+
+    ///The chips cover an area rather than a strip: they span several rows, or there is a single chip.
+    ///Diffraction patterns need such a layout.
+    pub fn is_2d(&self) -> bool {
+        self.height > CHIP_SIZE || self.chips.len() == 1
+    }
+
+    ///Number of columns covered by the chips, gaps included.
+    pub fn width(&self) -> POSITION {
+        self.width
+    }
+
+    ///Number of rows covered by the chips, gaps included.
+    pub fn height(&self) -> POSITION {
+        self.height
+    }
+
+    ///Position on the detector of the pixel (`x`, `y`) of chip `ci`. None if the chip is not in the layout.
+    #[inline]
+    pub fn position(&self, ci: u8, x: POSITION, y: POSITION) -> Option<(POSITION, POSITION)> {
+        let chip = self.chips.get(ci as usize)?;
+        let (x, y) = if chip.transpose {(y, x)} else {(x, y)};
+        let x = if chip.flip_x {CHIP_SIZE - 1 - x} else {x};
+        let y = if chip.flip_y {CHIP_SIZE - 1 - y} else {y};
+        Some((chip.column * (CHIP_SIZE + self.gap) + x, chip.row * (CHIP_SIZE + self.gap) + y))
+    }
+
+    ///Chip index and chip pixel of the detector position (`x`, `y`), the inverse of `position`. None
+    ///in a gap or outside the chips.
+    pub fn raw_position(&self, x: POSITION, y: POSITION) -> Option<(u8, POSITION, POSITION)> {
+        let step = CHIP_SIZE + self.gap;
+        let (column, row, x, y) = (x / step, y / step, x % step, y % step);
+        if x >= CHIP_SIZE || y >= CHIP_SIZE {return None;}
+        let ci = self.chips.iter().position(|chip| (chip.column, chip.row) == (column, row))?;
+        let chip = &self.chips[ci];
+        let x = if chip.flip_x {CHIP_SIZE - 1 - x} else {x};
+        let y = if chip.flip_y {CHIP_SIZE - 1 - y} else {y};
+        let (x, y) = if chip.transpose {(y, x)} else {(x, y)};
+        Some((ci as u8, x, y))
+    }
+}
@@ -13,3 +13,13 @@ pub mod speclib;
 pub mod spimlib;
 pub mod errorlib;
 pub mod clusterlib;
+pub mod modelib;
+pub mod broadcastlib;
+pub mod recordlib;
+pub mod controllib;
+pub mod statslib;
+pub mod layoutlib;
+pub mod calibrationlib;
+pub mod simulatelib;
+pub mod sortlib;
+pub mod timelib;
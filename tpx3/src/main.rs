@@ -1,71 +1,36 @@
 use timepix3::errorlib::Tp3ErrorKind;
 use timepix3::auxiliar::*;
-use timepix3::tdclib::*;
-use timepix3::{speclib, spimlib, spimlib::SpimKind};
+use timepix3::modelib::AcquisitionMode;
+use timepix3::broadcastlib::Broadcaster;
+use timepix3::statslib;
+use timepix3::layoutlib;
+use std::{env, thread, time};
 
 
-fn connect_and_loop() -> Result<u8, Tp3ErrorKind> {
+fn connect_and_loop(config: &NetworkConfig, broadcaster: &Broadcaster) -> Result<u8, Tp3ErrorKind> {
     
-    let (my_settings, mut pack, ns) = Settings::create_settings([192, 168, 199, 11], 8088)?;
-
-    match my_settings.mode {
-        0 if my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Live1D)?;
-            Ok(my_settings.mode)
-        },
-        0 if !my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Live2D)?;
-            Ok(my_settings.mode)
-        },
-        1 if my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let laser_tdc = SingleTriggerPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, laser_tdc, speclib::LiveTR1D)?;
-            Ok(my_settings.mode)
-        },
-        1 if !my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let laser_tdc = SingleTriggerPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, laser_tdc, speclib::LiveTR2D)?;
-            Ok(my_settings.mode)
-        },
-        2 => {
-            let spim_tdc = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut pack, Some(my_settings.yspim_size))?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            let measurement = spimlib::Live::new();
-            spimlib::build_spim(pack, ns, my_settings, spim_tdc, np_tdc, measurement)?;
-            Ok(my_settings.mode)
-        },
-        6 => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::FastChrono)?;
-            Ok(my_settings.mode)
-        },
-        7 => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Chrono)?;
-            Ok(my_settings.mode)
-        },
-        _ => Err(Tp3ErrorKind::MiscModeNotImplemented(my_settings.mode)),
-    }
+    let (my_settings, pack, ns) = Settings::create_settings(config, broadcaster)?;
+    AcquisitionMode::find(my_settings.mode)?.run(pack, ns, my_settings)
 }
 
-fn main() {
+fn main() -> Result<(), Tp3ErrorKind> {
+    let args: Vec<String> = env::args().collect();
+    let config = NetworkConfig::new(&args)?;
+    layoutlib::set_layout(config.layout.clone())?;
+    let broadcaster = Broadcaster::new();
+    if let Some(addr) = config.broadcast_addr {broadcaster.listen(addr)?;}
+    if let Some(addr) = config.stats_addr {statslib::listen(addr)?;}
     let mut log_file = simple_log::start().unwrap();
     loop {
-        match connect_and_loop() {
+        match connect_and_loop(&config, &broadcaster) {
             Ok(val) => {
                 simple_log::ok(&mut log_file, val).unwrap();
             },
             Err(e) => {
                 println!("Error in measurement. Error message: {:?}.", e);
+                let busy = matches!(e, Tp3ErrorKind::NetBind(_));
                 simple_log::error(&mut log_file, e).unwrap();
+                if busy {thread::sleep(time::Duration::from_millis(1000));}
             },
         }
     }
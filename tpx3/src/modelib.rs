@@ -0,0 +1,216 @@
+//!`modelib` is the registry of acquisition modes. Each mode number sent by the client is mapped to
+//!its default TDC wiring, the measurement that runs it (`SpecKind` or `SpimKind`) and its frame
+//!geometry. Adding a mode means adding one entry to `MODES`.
+
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::{Settings, misc::TimepixRead};
+use crate::tdclib::{TdcType, TdcRefKind, isi_box, isi_box::{CHANNELS, IsiBoxTools}};
+use crate::packetlib::PacketEELS;
+use crate::layoutlib::layout;
+use crate::speclib::{self, IsiBoxKind};
+use crate::spimlib::{self, SpimKind};
+use crate::auxiliar::value_types::*;
+use crate::{isi_box_new, tdc_dispatch};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::{thread, time};
+
+///Starts a measurement. The Timepix3 and client streams are those returned by `Settings::create_settings`.
+pub type Runner = fn(Box<dyn TimepixRead + Send>, Box<dyn Write + Send>, Settings) -> Result<(), Tp3ErrorKind>;
+
+///Same as `Runner`, also receiving the IsiBox address.
+pub type IsiRunner = fn(Box<dyn TimepixRead + Send>, Box<dyn Write + Send>, Settings, SocketAddr) -> Result<(), Tp3ErrorKind>;
+
+///Shape of the frames sent to the client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameGeometry {
+    ///One line if binned, the full camera height otherwise.
+    Spectrum,
+    ///`xspim_size` binned spectra stacked in time.
+    Chrono,
+    ///No frame is sent. Data is a list of indexes (spectral image) or saved locally.
+    NoFrame,
+}
+
+pub struct AcquisitionMode {
+    pub id: u8,
+    pub name: &'static str,
+    ///Frame (or line) TDC, reference TDC and reference kind used when the client does not choose them.
+    pub tdc: (TdcType, TdcType, TdcRefKind),
+    pub geometry: FrameGeometry,
+    ///The mode neither reads the Timepix3 socket nor writes to the client.
+    pub local_only: bool,
+    run: Option<Runner>,
+    run_isi: Option<IsiRunner>,
+}
+
+///Diffraction modes need a detector layout covering an area (see `DetectorLayout::is_2d`).
+fn require_2d_layout() -> Result<(), Tp3ErrorKind> {
+    match layout().is_2d() {
+        true => Ok(()),
+        false => Err(Tp3ErrorKind::SetDetectorLayout),
+    }
+}
+
+macro_rules! spectrum {
+    ($x: expr) => {
+        spectrum!($x, $x)
+    };
+    ($bin: expr, $unbin: expr) => {
+        |pack, ns, settings: Settings| {
+            tdc_dispatch!(settings, pack, None, |frame_tdc, ref_tdc| {
+                match settings.bin {
+                    true => speclib::run_spectrum(pack, ns, settings, frame_tdc, ref_tdc, $bin)?,
+                    false => speclib::run_spectrum(pack, ns, settings, frame_tdc, ref_tdc, $unbin)?,
+                };
+            });
+            Ok(())
+        }
+    };
+}
+
+const MODES: [AcquisitionMode; 8] = [
+    AcquisitionMode {
+        id: 0,
+        name: "Focus/Cumul",
+        tdc: (TdcType::TdcOneRisingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::Spectrum,
+        local_only: false,
+        run: Some(spectrum!(speclib::Live1D, speclib::Live2D)),
+        run_isi: Some(|pack, ns, settings, isibox_addr| {
+            if !settings.bin {return Err(Tp3ErrorKind::IsiBoxAttempt(settings.mode));}
+            let meas = speclib::SpecMeasurement::<speclib::Live1D, u32>::isi_new(&settings);
+            tdc_dispatch!(settings, pack, None, |frame_tdc, ref_tdc| {
+                speclib::build_spectrum_isi(pack, ns, settings, frame_tdc, ref_tdc, meas, isibox_addr)?;
+            });
+            Ok(())
+        }),
+    },
+    AcquisitionMode {
+        id: 1,
+        name: "Time resolved (Focus/Cumul)",
+        tdc: (TdcType::TdcOneRisingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::SingleTriggerPeriodic),
+        geometry: FrameGeometry::Spectrum,
+        local_only: false,
+        run: Some(spectrum!(speclib::LiveTR1D, speclib::LiveTR2D)),
+        run_isi: None,
+    },
+    AcquisitionMode {
+        id: 2,
+        name: "Spectral Image (SpimTP)",
+        tdc: (TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::NoFrame,
+        local_only: false,
+        run: Some(|pack, ns, settings| {
+            tdc_dispatch!(settings, pack, Some(settings.yspim_size), |spim_tdc, ref_tdc| {
+                spimlib::build_spim(pack, ns, settings, spim_tdc, ref_tdc, spimlib::Live::new())?;
+            });
+            Ok(())
+        }),
+        run_isi: Some(|pack, ns, settings, isibox_addr| {
+            tdc_dispatch!(settings, pack, Some(settings.yspim_size), |spim_tdc, ref_tdc| {
+                spimlib::build_spim_isi(pack, ns, settings, spim_tdc, ref_tdc, spimlib::Live::new(), isibox_addr)?;
+            });
+            Ok(())
+        }),
+    },
+    AcquisitionMode {
+        id: 6,
+        name: "Fast Chrono",
+        tdc: (TdcType::TdcOneRisingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::Chrono,
+        local_only: false,
+        run: Some(spectrum!(speclib::FastChrono)),
+        run_isi: None,
+    },
+    AcquisitionMode {
+        id: 7,
+        name: "Chrono",
+        tdc: (TdcType::TdcOneRisingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::Chrono,
+        local_only: false,
+        run: Some(spectrum!(speclib::Chrono)),
+        run_isi: None,
+    },
+    AcquisitionMode {
+        id: 8,
+        name: "Spectral Image [Save Locally] (IsiBox)",
+        tdc: (TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::NoFrame,
+        local_only: true,
+        run: None,
+        run_isi: Some(|_pack, _ns, _settings, isibox_addr| {
+            let mut handler = isi_box_new!(spec);
+            handler.bind_and_connect(isibox_addr)?;
+            handler.configure_scan_parameters(32, 32, 8334);
+            handler.configure_measurement_type(true);
+            thread::sleep(time::Duration::from_millis(1000));
+            Ok(())
+        }),
+    },
+    AcquisitionMode {
+        id: 10,
+        name: "4D-STEM (SpimTP)",
+        tdc: (TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::NoFrame,
+        local_only: false,
+        run: Some(|pack, ns, settings| {
+            require_2d_layout()?;
+            tdc_dispatch!(settings, pack, Some(settings.yspim_size), |spim_tdc, ref_tdc| {
+                spimlib::build_spim(pack, ns, settings, spim_tdc, ref_tdc, spimlib::Live4D::new())?;
+            });
+            Ok(())
+        }),
+        run_isi: None,
+    },
+    AcquisitionMode {
+        id: 11,
+        name: "4D-STEM Virtual Detectors (SpimTP)",
+        tdc: (TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcRefKind::NonPeriodic),
+        geometry: FrameGeometry::NoFrame,
+        local_only: false,
+        run: Some(|pack, ns, settings| {
+            require_2d_layout()?;
+            tdc_dispatch!(settings, pack, Some(settings.yspim_size), |spim_tdc, ref_tdc| {
+                spimlib::build_spim(pack, ns, settings, spim_tdc, ref_tdc, spimlib::LiveVirtual::new())?;
+            });
+            Ok(())
+        }),
+        run_isi: None,
+    },
+];
+
+impl AcquisitionMode {
+    ///Looks up a mode in the registry.
+    pub fn find(mode: u8) -> Result<&'static AcquisitionMode, Tp3ErrorKind> {
+        MODES.iter()
+            .find(|entry| entry.id == mode)
+            .ok_or(Tp3ErrorKind::MiscModeNotImplemented(mode))
+    }
+
+    ///Number of lines in a frame sent to the client.
+    pub fn lines(&self, settings: &Settings) -> POSITION {
+        match self.geometry {
+            FrameGeometry::Spectrum if settings.bin => 1,
+            FrameGeometry::Spectrum => PacketEELS::chip_array().1,
+            FrameGeometry::Chrono => settings.xspim_size,
+            FrameGeometry::NoFrame => 0,
+        }
+    }
+
+    ///Runs the mode using the Timepix3 detector only.
+    pub fn run(&self, pack: Box<dyn TimepixRead + Send>, ns: Box<dyn Write + Send>, settings: Settings) -> Result<u8, Tp3ErrorKind> {
+        let run = self.run.ok_or(Tp3ErrorKind::MiscModeNotImplemented(self.id))?;
+        println!("Entering in {}.", self.name);
+        run(pack, ns, settings)?;
+        Ok(self.id)
+    }
+
+    ///Runs the mode using both the Timepix3 detector and the IsiBox.
+    pub fn run_isi(&self, pack: Box<dyn TimepixRead + Send>, ns: Box<dyn Write + Send>, settings: Settings, isibox_addr: SocketAddr) -> Result<u8, Tp3ErrorKind> {
+        let run = self.run_isi.ok_or(Tp3ErrorKind::IsiBoxAttempt(self.id))?;
+        println!("Entering in {} with the IsiBox.", self.name);
+        run(pack, ns, settings, isibox_addr)?;
+        Ok(self.id)
+    }
+}
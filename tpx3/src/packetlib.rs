@@ -2,30 +2,120 @@
 //!in around `Packet` struct.
 
 use crate::auxiliar::value_types::*;
+use crate::layoutlib::layout;
+use crate::calibrationlib::time_calibration;
+use crate::tdclib::ELECTRON_TICK;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::slice::ChunksExact;
+
+///First four bytes of a chip header ("TPX3"). The fifth one is the chip index of the following packets.
+const CHIP_HEADER: u64 = 0x33_58_50_54;
+
+///Packets of a `PacketStream`. Chip headers are consumed by the stream and not returned. Pixel hits of
+///chips missing from the detector layout are returned as `Other`, so loops count them as ignored.
+pub enum StreamPacket<P> {
+    Electron(P),
+    Tdc(P),
+    Other(P),
+}
+
+///Decoding state kept across buffers: the chip index of the last header and the bytes of a packet
+///split between two buffers.
+#[derive(Default)]
+pub struct StreamState {
+    last_ci: u8,
+    partial: [u8; 8],
+    partial_len: usize,
+}
 
-pub fn packet_change(v: &[u8]) -> &[u64] {
-    unsafe {
-        std::slice::from_raw_parts(
-            v.as_ptr() as *const u64,
-            v.len() * std::mem::size_of::<u8>() / std::mem::size_of::<u64>())
+impl StreamState {
+    pub fn new() -> Self {
+        StreamState::default()
+    }
+
+    ///Chip index of the last chip header.
+    pub fn last_ci(&self) -> u8 {
+        self.last_ci
+    }
+
+    ///Decodes the packets of `data`. The buffer can have any alignment and any size; an incomplete
+    ///packet at its end is completed by the next buffer.
+    pub fn packets<'a, P: Packet>(&'a mut self, data: &'a [u8]) -> PacketStream<'a, P> {
+        let mut head = None;
+        let mut data = data;
+        if self.partial_len > 0 {
+            let missing = (8 - self.partial_len).min(data.len());
+            self.partial[self.partial_len..self.partial_len + missing].copy_from_slice(&data[..missing]);
+            self.partial_len += missing;
+            data = &data[missing..];
+            if self.partial_len == 8 {
+                head = Some(u64::from_le_bytes(self.partial));
+                self.partial_len = 0;
+            }
+        }
+        let body = data.len() - data.len() % 8;
+        let tail = &data[body..];
+        self.partial[self.partial_len..self.partial_len + tail.len()].copy_from_slice(tail);
+        self.partial_len += tail.len();
+        PacketStream {
+            chips: layout().chips().len(),
+            last_ci: &mut self.last_ci,
+            head,
+            chunks: data[..body].chunks_exact(8),
+            packet: PhantomData,
+        }
+    }
+}
+
+///Iterator over the packets of a buffer, with the chip index resolved. See `StreamState::packets`.
+pub struct PacketStream<'a, P> {
+    chips: usize,
+    last_ci: &'a mut u8,
+    head: Option<u64>,
+    chunks: ChunksExact<'a, u8>,
+    packet: PhantomData<P>,
+}
+
+impl<'a, P: Packet> Iterator for PacketStream<'a, P> {
+    type Item = StreamPacket<P>;
+
+    fn next(&mut self) -> Option<StreamPacket<P>> {
+        loop {
+            let data = match self.head.take() {
+                Some(data) => data,
+                None => u64::from_le_bytes(self.chunks.next()?.try_into().unwrap()),
+            };
+            if data & 0xFF_FF_FF_FF == CHIP_HEADER {
+                *self.last_ci = (data >> 32) as u8;
+                continue;
+            }
+            let packet = P::new(*self.last_ci, data);
+            return Some(match packet.id() {
+                11 if (packet.ci() as usize) < self.chips => StreamPacket::Electron(packet),
+                6 => StreamPacket::Tdc(packet),
+                _ => StreamPacket::Other(packet),
+            });
+        }
     }
 }
 
 pub trait Packet {
+    fn new(chip_index: u8, data: u64) -> Self where Self: Sized;
     fn ci(&self) -> u8;
     fn data(&self) -> u64;
 
+    ///Position on the detector, as given by the detector layout. None if the chip is not in the layout.
+    #[inline]
+    fn position(&self) -> Option<(POSITION, POSITION)> {
+        layout().position(self.ci(), self.x_raw(), self.y_raw())
+    }
+
+    ///Column on the detector. Pixels of a chip missing from the layout keep their chip column.
     #[inline]
     fn x(&self) -> POSITION {
-        let temp2 = (((self.data() & 0x0F_E0_00_00_00_00_00_00) >> 52) | ((self.data() & 0x00_00_40_00_00_00_00_00) >> 46)) as POSITION;
-        
-        match self.ci() {
-            0 => 255 - temp2,
-            1 => 256 * 4 - 1 - temp2,
-            2 => 256 * 3 - 1 - temp2,
-            3 => 256 * 2 - 1 - temp2,
-            _ => panic!("More than four CIs."),
-        }
+        self.position().map_or(self.x_raw(), |(x, _)| x)
     }
     
     #[inline]
@@ -33,8 +123,14 @@ pub trait Packet {
         (((self.data() & 0x0F_E0_00_00_00_00_00_00) >> 52) | ((self.data() & 0x00_00_40_00_00_00_00_00) >> 46)) as POSITION
     }
     
+    ///Row on the detector. Pixels of a chip missing from the layout keep their chip row.
     #[inline]
     fn y(&self) -> POSITION {
+        self.position().map_or(self.y_raw(), |(_, y)| y)
+    }
+
+    #[inline]
+    fn y_raw(&self) -> POSITION {
         (((self.data() & 0x00_1F_80_00_00_00_00_00) >> 45) | ((self.data() & 0x00_00_30_00_00_00_00_00) >> 44)) as POSITION
     }
 
@@ -164,6 +260,107 @@ pub trait Packet {
     fn tdc_overflow() -> TIME {
         68_719_476_736
     }
+
+    ///Second half of the header byte. Edge and input line for TDCs, packet subtype otherwise.
+    #[inline]
+    fn subtype(&self) -> u8 {
+        ((self.data() & 0x0F_00_00_00_00_00_00_00) >> 56) as u8
+    }
+
+    fn kind(&self) -> PacketKind {
+        let data = self.data();
+        match self.id() {
+            11 => PacketKind::Electron,
+            10 => PacketKind::PixelCount,
+            6 => PacketKind::Tdc,
+            4 => match self.subtype() {
+                5 => PacketKind::GlobalTime { msb: true, time: (data >> 16) & 0xFF_FF },
+                _ => PacketKind::GlobalTime { msb: false, time: (data >> 16) & 0xFF_FF_FF_FF },
+            },
+            5 => {
+                let time = (data >> 12) & 0x03_FF_FF_FF_FF;
+                match self.subtype() {
+                    15 => PacketKind::ShutterOpen { time },
+                    10 => PacketKind::ShutterClose { time },
+                    12 => PacketKind::Heartbeat { time },
+                    subtype => PacketKind::SpidrControl { subtype },
+                }
+            },
+            7 => PacketKind::Control { subtype: self.subtype(), command: ((data >> 48) & 0xFF) as u8 },
+            9 => PacketKind::PixelConfig,
+            id => PacketKind::Unknown(id),
+        }
+    }
+}
+
+///Packet families, from the header nibble (`Packet::id`) and the subtype nibble (`Packet::subtype`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    ///Pixel hit with ToA and ToT (0xB).
+    Electron,
+    ///Pixel hit in event count or integral ToT mode (0xA).
+    PixelCount,
+    ///TDC event (0x6). Edge and input line are given by `tdc_type`.
+    Tdc,
+    ///Global time (0x44 for the 32 lower bits, 0x45 for the 16 upper bits). In units of 25 ns.
+    GlobalTime { msb: bool, time: TIME },
+    ///SPIDR shutter open (0x5F). Time in units of 25 ns.
+    ShutterOpen { time: TIME },
+    ///SPIDR shutter close (0x5A). Time in units of 25 ns.
+    ShutterClose { time: TIME },
+    ///SPIDR heartbeat (0x5C). Time in units of 25 ns.
+    Heartbeat { time: TIME },
+    ///Any other SPIDR control packet (0x5).
+    SpidrControl { subtype: u8 },
+    ///Timepix3 control (0x7), for example the end of a readout (0x71A0 and 0x71B0).
+    Control { subtype: u8, command: u8 },
+    ///Readback of the pixel configuration (0x9). Address is decoded as for pixel hits.
+    PixelConfig,
+    Unknown(u8),
+}
+
+///Counts the packets a decoding loop does not use, by family. The live loops still follow the shutter
+///and the global time of these packets (see `statslib::FrameStats::add_other`).
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IgnoredPackets {
+    pub electron: u64,
+    pub pixel_count: u64,
+    pub tdc: u64,
+    pub global_time: u64,
+    pub shutter_open: u64,
+    pub shutter_close: u64,
+    pub heartbeat: u64,
+    pub spidr_control: u64,
+    pub control: u64,
+    pub pixel_config: u64,
+    pub unknown: u64,
+}
+
+impl IgnoredPackets {
+    ///Counts the packet and returns its kind, so the caller can still react to it.
+    pub fn add<P: Packet>(&mut self, packet: &P) -> PacketKind {
+        let kind = packet.kind();
+        match kind {
+            PacketKind::Electron => self.electron += 1,
+            PacketKind::PixelCount => self.pixel_count += 1,
+            PacketKind::Tdc => self.tdc += 1,
+            PacketKind::GlobalTime { .. } => self.global_time += 1,
+            PacketKind::ShutterOpen { .. } => self.shutter_open += 1,
+            PacketKind::ShutterClose { .. } => self.shutter_close += 1,
+            PacketKind::Heartbeat { .. } => self.heartbeat += 1,
+            PacketKind::SpidrControl { .. } => self.spidr_control += 1,
+            PacketKind::Control { .. } => self.control += 1,
+            PacketKind::PixelConfig => self.pixel_config += 1,
+            PacketKind::Unknown(_) => self.unknown += 1,
+        }
+        kind
+    }
+
+    pub fn total(&self) -> u64 {
+        self.electron + self.pixel_count + self.tdc + self.global_time + self.shutter_open + self.shutter_close +
+            self.heartbeat + self.spidr_control + self.control + self.pixel_config + self.unknown
+    }
 }
 
 pub struct PacketEELS {
@@ -172,6 +369,9 @@ pub struct PacketEELS {
 }
 
 impl Packet for PacketEELS {
+    fn new(chip_index: u8, data: u64) -> Self {
+        PacketEELS { chip_index, data }
+    }
     fn ci(&self) -> u8 {
         self.chip_index
     }
@@ -181,8 +381,9 @@ impl Packet for PacketEELS {
 }
 
 impl PacketEELS {
-    pub const fn chip_array() -> (POSITION, POSITION) {
-        (1025, 256)
+    ///Frame size of the EELS modes. The last column holds the TDC hits.
+    pub fn chip_array() -> (POSITION, POSITION) {
+        (layout().width() + 1, layout().height())
     }
 }
 
@@ -192,6 +393,9 @@ pub struct TimeCorrectedPacketEELS {
 }
 
 impl Packet for TimeCorrectedPacketEELS {
+    fn new(chip_index: u8, data: u64) -> Self {
+        TimeCorrectedPacketEELS { chip_index, data }
+    }
     fn ci(&self) -> u8 {
         self.chip_index
     }
@@ -218,58 +422,83 @@ impl Packet for TimeCorrectedPacketEELS {
 }
 
 impl TimeCorrectedPacketEELS {
-    pub const fn chip_array() -> (POSITION, POSITION) {
-        (1025, 256)
+    pub fn chip_array() -> (POSITION, POSITION) {
+        PacketEELS::chip_array()
     }
 }
 
-pub struct PacketDiffraction {
+///EELS packet whose electron time is corrected by the timing calibration of the process (see
+///`calibrationlib`). Without calibration, it is the same as `TimeCorrectedPacketEELS`.
+pub struct CalibratedPacketEELS {
     pub chip_index: u8,
     pub data: u64,
 }
 
-impl Packet for PacketDiffraction {
+impl Packet for CalibratedPacketEELS {
+    fn new(chip_index: u8, data: u64) -> Self {
+        CalibratedPacketEELS { chip_index, data }
+    }
     fn ci(&self) -> u8 {
         self.chip_index
     }
     fn data(&self) -> u64 {
         self.data
     }
-    /*
-    fn x(&self) -> POSITION {
-        let temp = (((self.data[6] & 224)>>4 | (self.data[7] & 15)<<4) | (((self.data[5] & 112)>>4)>>2)) as POSITION;
-        match self.chip_index {
-            0 => 255 - temp,
-            1 => temp,
-            2 => temp + 256,
-            3 => 256 * 2 - 1 - temp,
-            _ => panic!("More than four CI."),
-        }
+
+    fn fast_electron_time(&self) -> TIME {
+        let spidr = self.spidr();
+        let toa = self.toa();
+        spidr * 262_144 + toa * 16
     }
 
-    fn y(&self) -> POSITION {
-        let temp = (   ( (self.data[5] & 128)>>5 | (self.data[6] & 31)<<3 ) | ( ((self.data[5] & 112)>>4) & 3 )   ) as POSITION;
-        match self.chip_index {
-            0 => temp,
-            1 => 256 * 2 - 1 - temp,
-            2 => 256 * 2 - 1 - temp,
-            3 => temp,
-            _ => panic!("More than four CI."),
+    fn electron_time(&self) -> TIME {
+        match time_calibration() {
+            Some(calibration) => {
+                let t = (self.spidr() * 262_144 + self.ctoa()) as i64;
+                let correction = (calibration.correction(self.x(), self.y(), self.tot()) as f64 / ELECTRON_TICK).round() as i64;
+                (t - correction).rem_euclid(Self::electron_overflow() as i64) as TIME
+            },
+            None => TimeCorrectedPacketEELS { chip_index: self.chip_index, data: self.data }.electron_time(),
         }
     }
-    */
+}
+
+impl CalibratedPacketEELS {
+    pub fn chip_array() -> (POSITION, POSITION) {
+        PacketEELS::chip_array()
+    }
+}
+
+pub struct PacketDiffraction {
+    pub chip_index: u8,
+    pub data: u64,
+}
+
+impl Packet for PacketDiffraction {
+    fn new(chip_index: u8, data: u64) -> Self {
+        PacketDiffraction { chip_index, data }
+    }
+    fn ci(&self) -> u8 {
+        self.chip_index
+    }
+    fn data(&self) -> u64 {
+        self.data
+    }
 }
 
 impl PacketDiffraction {
-    pub const fn chip_array() -> (POSITION, POSITION) {
-        (512, 512)
+    pub fn chip_array() -> (POSITION, POSITION) {
+        (layout().width(), layout().height())
     }
 }
 
+///Encodes synthetic packets, the inverse of `Packet`. Times are in ns and positions are detector
+///positions, placed on the chips by the detector layout. See `simulatelib` for whole streams.
 pub struct InversePacket {
     pub x: usize,
     pub y: usize,
     pub time: usize,
+    pub tot: usize,
     pub id: usize,
 }
 
@@ -278,13 +507,11 @@ use crate::tdclib::TdcType;
 impl InversePacket {
 
     pub fn new_inverse_electron(x: usize, y: usize, time: usize) -> Self {
-        
-        let x = (!x & 255) | (x & 768);
-
         InversePacket {
             x,
             y,
             time,
+            tot: 1023,
             id: 11
         }
     }
@@ -295,32 +522,33 @@ impl InversePacket {
             x: 0,
             y: 0,
             time,
+            tot: 0,
             id: 6,
         }
     }
 
 
+    pub fn with_tot(mut self, tot: usize) -> Self {
+        self.tot = tot;
+        self
+    }
+
+    ///Chip header and electron packet. Panics if the position is not on a chip of the detector layout.
     pub fn create_electron_array(&self) -> [u8; 16] {
         let (spidr, toa_ticks, ftoa_ticks) = self.time_to_ticks();
-        let tot_ticks = 1023;
-        let x_raw = self.x % 256;
-        let mut ci: u8 = (self.x >> 8) as u8;
-
-        ci = if ci == 1 {
-            3
-        } else if ci == 3 {
-            1
-        } else {
-            ci
+        let tot_ticks = self.tot;
+        let (ci, x_raw, y_raw) = match layout().raw_position(self.x as POSITION, self.y as POSITION) {
+            Some((ci, x_raw, y_raw)) => (ci, x_raw as usize, y_raw as usize),
+            None => panic!("Position ({}, {}) is not on the detector.", self.x, self.y),
         };
 
         let data0: u8 = (spidr & 255) as u8;
         let data1: u8 = ((spidr & 65_280) >> 8) as u8;
-        let data2: u8 = (!ftoa_ticks | (tot_ticks & 15) << 4) as u8;
+        let data2: u8 = ((!ftoa_ticks & 15) | (tot_ticks & 15) << 4) as u8;
         let data3: u8 = ((tot_ticks & 1_008) >> 4 | (toa_ticks & 3) << 6) as u8;
         let data4: u8 = ((toa_ticks & 1_020) >> 2) as u8;
-        let data5: u8 = ((x_raw & 1) << 6 | (self.y & 4) << 5 | (self.y & 3) << 4 | (toa_ticks & 15_360) >> 10) as u8;
-        let data6: u8 = ((self.y & 248) >> 3 | (x_raw & 14) << 4) as u8;
+        let data5: u8 = ((x_raw & 1) << 6 | (y_raw & 4) << 5 | (y_raw & 3) << 4 | (toa_ticks & 15_360) >> 10) as u8;
+        let data6: u8 = ((y_raw & 248) >> 3 | (x_raw & 14) << 4) as u8;
         let data7: u8 = ((self.id & 15) << 4 | (x_raw & 240) >> 4) as u8;
         [84, 80, 88, 51, ci, 0, 8, 0, data0, data1, data2, data3, data4, data5, data6, data7]
     }
@@ -341,33 +569,6 @@ impl InversePacket {
         [84, 80, 88, 51, 0, 0, 8, 0, data0, data1, data2, data3, data4, data5, data6, data7]
     }
 
-
-    /*
-    pub fn test_func(&self) {
-        let my_inv_packet = InversePacket::new_inverse_electron(128, 100, 3_111_005);
-
-        let my_data = my_inv_packet.create_electron_array();
-        let my_packet = PacketEELS {
-            chip_index: my_data[4],
-            data: &my_data[8..16].try_into().unwrap()
-        };
-
-        println!("{} and {} and {} and {} and {} and {}", my_packet.x(), my_packet.y(), my_packet.x_raw(), my_packet.electron_time(), !my_packet.ftoa() & 15, my_packet.tot());
-    }
-
-    pub fn tdc_test_func(&self) {
-        let my_inv_packet = InversePacket::new_inverse_tdc(26_844_000_000);
-
-        let my_data = my_inv_packet.create_tdc_array(1024, TdcType::TdcTwoFallingEdge);
-        let my_packet = PacketEELS {
-            chip_index: my_data[4],
-            data: &my_data[8..16].try_into().unwrap()
-        };
-
-        println!("{} and {} and {} and {}", my_packet.id(), my_packet.tdc_time_norm(), my_packet.tdc_time(), my_packet.tdc_type());
-    }
-    */
-
     pub fn time_to_ticks(&self) -> (usize, usize, usize) {
         let spidr_ticks = self.time / 409_600;
         let ctoa = self.time % 409_600;
@@ -376,11 +577,110 @@ impl InversePacket {
         (spidr_ticks, toa_ticks, ftoa_ticks)
     }
     
+    ///Coarse ticks of 3.125 ns and fine ticks of 260 ps.
     pub fn tdc_time_to_ticks(&self) -> (usize, usize) {
         let coarse_ticks = self.time * 320 / 1_000;
-        let fine_time = ((self.time * 1000) % 3125) / 1000;
-        let fine_ticks = fine_time * 1_000 / 260;
+        let fine_ticks = ((self.time * 1000) % 3125) * 12 / 3125;
  
         (coarse_ticks, fine_ticks)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const TDC_KINDS: [TdcType; 4] = [TdcType::TdcOneRisingEdge, TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcType::TdcTwoFallingEdge];
+
+    ///Decodes a chip header and its packet as the acquisition does.
+    fn decode(array: &[u8; 16]) -> PacketEELS {
+        let mut stream = StreamState::new();
+        let mut packets = stream.packets::<PacketEELS>(array).map(|packet| match packet {
+            StreamPacket::Electron(packet) | StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => packet,
+        });
+        let packet = packets.next().expect("No packet decoded.");
+        assert!(packets.next().is_none());
+        packet
+    }
+
+    ///Electron time, in 1.5625 ns, of a time in ns.
+    fn electron_ticks(time: usize) -> TIME {
+        (time as TIME * 16 / 25) % PacketEELS::electron_overflow()
+    }
+
+    ///TDC time, in 260 ps, of a time in ns.
+    fn tdc_abs_ticks(time: usize) -> TIME {
+        (time as TIME * 12_000 / 3_125) % (PacketEELS::tdc_overflow() * 6)
+    }
+
+    proptest! {
+        #[test]
+        fn electron_round_trip(x in 0..1024usize, y in 0..256usize, time in 0..80_000_000_000usize, tot in 0..1024usize) {
+            let packet = decode(&InversePacket::new_inverse_electron(x, y, time).with_tot(tot).create_electron_array());
+            prop_assert_eq!(packet.id(), 11);
+            prop_assert_eq!(packet.ci(), [0, 3, 2, 1][x / 256]);
+            prop_assert_eq!(packet.x(), x as POSITION);
+            prop_assert_eq!(packet.y(), y as POSITION);
+            prop_assert_eq!(packet.tot(), tot as u16);
+            prop_assert_eq!(packet.electron_time(), electron_ticks(time));
+        }
+
+        #[test]
+        fn fine_toa_is_inverted(time in 0..409_600usize) {
+            let packet = decode(&InversePacket::new_inverse_electron(0, 0, time).create_electron_array());
+            let fine = (time % 25) * 16 / 25;
+            prop_assert_eq!(packet.ftoa(), (!fine & 15) as TIME);
+            prop_assert_eq!(packet.electron_time() - packet.fast_electron_time(), fine as TIME);
+        }
+
+        #[test]
+        fn tdc_round_trip(time in 0..330_000_000_000usize, counter in 0..4096usize, kind in 0..4usize) {
+            let packet = decode(&InversePacket::new_inverse_tdc(time).create_tdc_array(counter, TDC_KINDS[kind]));
+            prop_assert_eq!(packet.id(), 6);
+            prop_assert_eq!(packet.tdc_type(), TDC_KINDS[kind].associate_value());
+            prop_assert_eq!(packet.tdc_counter(), counter as u16);
+            prop_assert_eq!(packet.tdc_time_abs(), tdc_abs_ticks(time));
+            prop_assert_eq!(packet.tdc_time_norm(), (tdc_abs_ticks(time) / 6) % PacketEELS::electron_overflow());
+        }
+
+        #[test]
+        fn electron_and_tdc_share_time_base(time in 0..80_000_000_000usize) {
+            let electron = decode(&InversePacket::new_inverse_electron(0, 0, time).create_electron_array());
+            let tdc = decode(&InversePacket::new_inverse_tdc(time).create_tdc_array(0, TdcType::TdcOneRisingEdge));
+            prop_assert_eq!(electron.electron_time(), tdc.tdc_time_norm());
+        }
+    }
+
+    #[test]
+    fn electron_time_wraps_at_overflow() {
+        let overflow_ns = 65_536 * 409_600;
+        for (time, expected) in [(overflow_ns - 1, PacketEELS::electron_overflow() - 1), (overflow_ns, 0), (overflow_ns + 25, 16)] {
+            let packet = decode(&InversePacket::new_inverse_electron(300, 20, time).create_electron_array());
+            assert_eq!(packet.electron_time(), expected);
+        }
+    }
+
+    #[test]
+    fn tdc_time_wraps_at_overflow() {
+        let overflow_ns = (1usize << 35) * 3_125 / 1_000;
+        let before = decode(&InversePacket::new_inverse_tdc(overflow_ns - 1).create_tdc_array(0, TdcType::TdcTwoFallingEdge));
+        let after = decode(&InversePacket::new_inverse_tdc(overflow_ns + 1).create_tdc_array(0, TdcType::TdcTwoFallingEdge));
+        assert_eq!(before.tdc_coarse(), (1 << 35) - 1);
+        assert_eq!(after.tdc_coarse(), 0);
+        assert!(after.tdc_time_norm() < before.tdc_time_norm());
+    }
+
+    #[test]
+    fn unknown_chip_is_not_an_electron() {
+        let mut array = InversePacket::new_inverse_electron(10, 20, 1_000).create_electron_array();
+        array[4] = layout().chips().len() as u8;
+        let mut stream = StreamState::new();
+        let packet = match stream.packets::<PacketEELS>(&array).next() {
+            Some(StreamPacket::Other(packet)) => packet,
+            _ => panic!("A pixel hit of an unknown chip must not be an electron."),
+        };
+        assert_eq!(packet.position(), None);
+        assert_eq!((packet.x(), packet.y()), (packet.x_raw(), packet.y_raw()));
+    }
+}
@@ -1,9 +1,12 @@
 pub mod coincidence {
 
     use std::fs::OpenOptions;
-    use crate::spimlib::SPIM_PIXELS;
-    use crate::packetlib::{Packet, TimeCorrectedPacketEELS as Pack, packet_change};
-    use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef, NonPeriodicTdcRef};
+    use crate::spimlib::spim_pixels;
+    use crate::packetlib::{Packet, CalibratedPacketEELS as Pack, IgnoredPackets, StreamState, StreamPacket};
+    use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef, NonPeriodicTdcRef, TdcDiscovery, ELECTRON_TICK};
+    use crate::calibrationlib::{TimeCalibration, time_calibration, TOT_RANGE};
+    use crate::sortlib::TimeSorter;
+    use crate::timelib::{Timeline, TimeBase};
     use crate::postlib::isi_box;
     use std::io;
     use std::io::prelude::*;
@@ -15,6 +18,9 @@ pub mod coincidence {
     use indicatif::{ProgressBar, ProgressStyle};
 
     const ISI_BUFFER_SIZE: usize = 512_000_000; //Buffer size reading files when using TP3 and IsiBox
+    const TIME_DELAY: TIME = 104; //Photon time minus electron time, without calibration
+    const TIME_WIDTH: TIME = 40; //Half width of the coincidence window, without calibration
+    const CALIBRATION_MIN_EVENTS: usize = 10; //Events needed to calibrate a ToT value or a pixel
     
     fn as_bytes<T>(v: &[T]) -> &[u8] {
         unsafe {
@@ -24,6 +30,18 @@ pub mod coincidence {
         }
     }
 
+    fn median(values: &mut [f32]) -> f32 {
+        values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        values[values.len() / 2]
+    }
+
+    ///Standard deviation estimated from the median absolute deviation, so outliers do not weight.
+    fn spread(values: &[f32]) -> f32 {
+        if values.is_empty() {return 0.0;}
+        let center = median(&mut values.to_vec());
+        1.4826 * median(&mut values.iter().map(|value| (value - center).abs()).collect::<Vec<f32>>())
+    }
+
     fn output_data<T>(data: &[T], name: &str) {
         let mut tfile = OpenOptions::new()
             .write(true)
@@ -50,7 +68,10 @@ pub mod coincidence {
         spim_index: Vec<POSITION>,
         spim_tdc: Option<PeriodicTdcRef>,
         remove_clusters: bool,
-        overflow_electrons: COUNTER,
+        energy_window: Option<(f32, f32)>,
+        timeline: Timeline,
+        time_delay: TIME,
+        time_width: TIME,
     }
 
     impl ElectronData {
@@ -59,26 +80,32 @@ pub mod coincidence {
         }
 
         fn add_spim_line(&mut self, pack: &Pack) {
+            let time = self.timeline.tdc_time(pack);
             if let Some(spim_tdc) = &mut self.spim_tdc {
-                spim_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+                spim_tdc.upt(time, pack.tdc_counter());
             }
         }
 
-        fn estimate_overflow(&self, pack: &Pack) -> Option<TIME> {
-            if let Some(spim_tdc) = self.spim_tdc {
-                let val = spim_tdc.estimate_time();
-                if val > pack.tdc_time() + Pack::electron_overflow() {
-                    return Some(val / Pack::tdc_overflow());
-                }
-                else {return Some(0)}
+        ///Checks if `tdc_type` is the other edge of the spim line, which stops the line.
+        fn is_spim_line_stop(&self, tdc_type: u8) -> bool {
+            self.spim_tdc.is_some_and(|spim_tdc| spim_tdc.is_opposite_edge(tdc_type))
+        }
+
+        fn add_spim_line_stop(&mut self, pack: &Pack) {
+            let time = self.timeline.tdc_time(pack);
+            if let Some(spim_tdc) = &mut self.spim_tdc {
+                spim_tdc.add_opposite_edge(time);
             }
-            None
+        }
+
+        fn new_electron(&mut self, pack: &Pack) -> SingleElectron {
+            SingleElectron::new(pack, self.timeline.electron_time(pack), self.spim_tdc)
         }
 
 
         fn add_coincident_electron(&mut self, val: SingleElectron, photon: (TIME, COUNTER, Option<i64>)) {
             self.corr_spectrum[val.x() as usize] += 1; //Adding the electron
-            self.corr_spectrum[SPIM_PIXELS as usize-1] += 1; //Adding the photon
+            self.corr_spectrum[spim_pixels() as usize-1] += 1; //Adding the photon
             self.time.push(val.time());
             self.g2_time.push(photon.2);
             self.tot.push(val.tot());
@@ -100,20 +127,13 @@ pub mod coincidence {
             let mut min_index = temp_tdc.min_index;
             //println!("Total supplementary events: {}. Photons: {}. Minimum size of the array: {}.", ntotal, nphotons, min_index);
 
-            match temp_tdc.tdc_type {
-                TempTdcDataType::FromTP3 => {
-                    temp_tdc.sort();
-                    if temp_edata.electron.check_if_overflow() {self.overflow_electrons += 1;}
-                },
-                TempTdcDataType::FromIsiBox => {
-                    if temp_edata.electron.correct_electron_time(self.overflow_electrons) {self.overflow_electrons += 1;}
-                },
-            }
+            if let TempTdcDataType::FromTP3 = temp_tdc.tdc_type {temp_tdc.sort();}
 
             temp_edata.electron.sort();
             temp_edata.electron.try_clean(0, self.remove_clusters);
+            if let Some((min, max)) = self.energy_window {temp_edata.electron.retain_energy(min, max);}
 
-            self.spectrum[SPIM_PIXELS as usize-1]=nphotons; //Adding photons to the last pixel
+            self.spectrum[spim_pixels() as usize-1]=nphotons; //Adding photons to the last pixel
 
             //let mut photon_vec = temp_tdc.tdc.iter().filter(|ph| ph.1 != 16 && ph.1 != 24).collect::<Vec<_>>();
             
@@ -146,6 +166,13 @@ pub mod coincidence {
         }
 
         pub fn new(my_config: &ConfigAcquisition) -> Self {
+            let (time_delay, time_width) = match time_calibration() {
+                Some(calibration) => (
+                    (calibration.coincidence_delay.max(0.0) as f64 / ELECTRON_TICK).round() as TIME,
+                    (calibration.coincidence_width as f64 / ELECTRON_TICK).ceil() as TIME,
+                ),
+                None => (TIME_DELAY, TIME_WIDTH),
+            };
             Self {
                 time: Vec::new(),
                 channel: Vec::new(),
@@ -155,22 +182,86 @@ pub mod coincidence {
                 y: Vec::new(),
                 tot: Vec::new(),
                 cluster_size: Vec::new(),
-                spectrum: vec![0; SPIM_PIXELS as usize*1],
-                corr_spectrum: vec![0; SPIM_PIXELS as usize*1],
+                spectrum: vec![0; spim_pixels() as usize*1],
+                corr_spectrum: vec![0; spim_pixels() as usize*1],
                 is_spim: my_config.is_spim,
                 spim_size: (my_config.xspim, my_config.yspim),
                 spim_index: Vec::new(),
                 spim_tdc: None,
                 remove_clusters: my_config.remove_cluster,
-                overflow_electrons: 0,
+                energy_window: my_config.energy_window,
+                timeline: Timeline::default(),
+                time_delay,
+                time_width,
+            }
+        }
+
+        ///Sets the coincidence window of `search_coincidence`, in units of 1.5625 ns.
+        pub fn set_coincidence_window(&mut self, time_delay: TIME, time_width: TIME) {
+            self.time_delay = time_delay;
+            self.time_width = time_width;
+        }
+
+        ///Derives the timing calibration from the coincident electrons, the photon TDC being the time
+        ///reference. The search must use a window holding the uncorrected spread. If a calibration is
+        ///loaded, the result includes it, so the calibration can be refined by running it again.
+        ///Clustered electrons whose ToT sum is above `TOT_RANGE` are not used.
+        pub fn time_calibration(&self) -> TimeCalibration {
+            self.refine_time_calibration(time_calibration().cloned().unwrap_or_else(TimeCalibration::empty))
+        }
+
+        ///Adds the correction measured from the coincidences to `calibration`, the one they were found with.
+        fn refine_time_calibration(&self, mut calibration: TimeCalibration) -> TimeCalibration {
+            let fine = TimeBase::Tdc.tick() as f32; //Unit of the relative time, in ns.
+            let (events, dt): (Vec<usize>, Vec<f32>) = self.rel_time.iter()
+                .enumerate()
+                .filter(|&(index, _)| (self.tot[index] as usize) < TOT_RANGE)
+                .map(|(index, &time)| (index, time as f32 * fine))
+                .unzip();
+            if dt.is_empty() {return calibration;}
+            let delay = median(&mut dt.clone());
+
+            //Time walk is the median delay of each ToT. Missing ToT values take their neighbour's.
+            let mut by_tot = vec![Vec::new(); TOT_RANGE];
+            events.iter().zip(dt.iter()).for_each(|(&index, &time)| by_tot[self.tot[index] as usize].push(time - delay));
+            let mut walk = by_tot.iter_mut()
+                .map(|times| if times.len() >= CALIBRATION_MIN_EVENTS {Some(median(times))} else {None})
+                .collect::<Vec<_>>();
+            let first = walk.iter().flatten().next().copied().unwrap_or(0.0);
+            walk.iter_mut().fold(first, |last, value| {*value = Some(value.unwrap_or(last)); value.unwrap()});
+            let walk = walk.into_iter().flatten().collect::<Vec<f32>>();
+
+            //Pixel offsets are the mean residual of each pixel. Accidental coincidences are rejected
+            //using the spread of the residuals.
+            let residual = events.iter().zip(dt.iter()).map(|(&index, &time)| time - delay - walk[self.tot[index] as usize]).collect::<Vec<f32>>();
+            let window = (3.0 * spread(&residual)).max(fine);
+            let mut sums = vec![(0.0, 0); calibration.offsets.len()];
+            let pixel = |index: usize| (self.x[index] + calibration.width * self.y[index]) as usize;
+            for (&index, &time) in events.iter().zip(residual.iter()) {
+                if let Some(sum) = sums.get_mut(pixel(index)) {
+                    if time.abs() < window {*sum = (sum.0 + time, sum.1 + 1);}
+                }
             }
+            let offsets = sums.iter()
+                .map(|&(sum, count)| if count >= CALIBRATION_MIN_EVENTS {sum / count as f32} else {0.0})
+                .collect::<Vec<f32>>();
+            let corrected = events.iter().zip(residual.iter())
+                .map(|(&index, &time)| time - offsets.get(pixel(index)).copied().unwrap_or(0.0))
+                .collect::<Vec<f32>>();
+
+            calibration.offsets.iter_mut().zip(offsets.iter()).for_each(|(a, b)| *a += b);
+            calibration.time_walk.iter_mut().zip(walk.iter()).for_each(|(a, b)| *a += b);
+            calibration.coincidence_delay = -delay;
+            calibration.coincidence_width = (3.0 * spread(&corrected)).max(ELECTRON_TICK as f32);
+            println!("Time calibration from {} coincidences. Spread went from {} ns to {} ns.", dt.len(), spread(&dt), spread(&corrected));
+            calibration
         }
         
         pub fn output_corr_spectrum(&self, bin: bool) {
             let out: String = match bin {
                 true => {
-                    let mut spec: Vec<usize> = vec![0; SPIM_PIXELS as usize];
-                    for val in self.corr_spectrum.chunks_exact(SPIM_PIXELS as usize) {
+                    let mut spec: Vec<usize> = vec![0; spim_pixels() as usize];
+                    for val in self.corr_spectrum.chunks_exact(spim_pixels() as usize) {
                         spec.iter_mut().zip(val.iter()).map(|(a, b)| *a += b).count();
                     }
                     spec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
@@ -185,8 +276,8 @@ pub mod coincidence {
         pub fn output_spectrum(&self, bin: bool) {
             let out: String = match bin {
                 true => {
-                    let mut spec: Vec<usize> = vec![0; SPIM_PIXELS as usize];
-                    for val in self.spectrum.chunks_exact(SPIM_PIXELS as usize) {
+                    let mut spec: Vec<usize> = vec![0; spim_pixels() as usize];
+                    for val in self.spectrum.chunks_exact(spim_pixels() as usize) {
                         spec.iter_mut().zip(val.iter()).map(|(a, b)| *a += b).count();
                     }
                     spec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
@@ -306,8 +397,8 @@ pub mod coincidence {
                 collect::<Vec<_>>()
         }
 
-        fn add_tdc(&mut self, my_pack: &Pack, channel: COUNTER) {
-            self.tdc.push((my_pack.tdc_time_abs_norm(), channel, None));
+        fn add_tdc(&mut self, time: TIME, channel: COUNTER) {
+            self.tdc.push((time, channel, None));
         }
 
         fn sort(&mut self) {
@@ -346,7 +437,7 @@ pub mod coincidence {
 
     pub fn search_coincidence(file: &str, coinc_data: &mut ElectronData) -> io::Result<()> {
 
-        let mut file0 = fs::File::open(file)?;
+        let mut file0 = TdcDiscovery::new(fs::File::open(file)?, Default::default());
         
         let spim_tdc: Box<dyn TdcControl> = if coinc_data.is_spim {
             if coinc_data.spim_size.0 == 0 || coinc_data.spim_size.1 == 0 {
@@ -360,53 +451,56 @@ pub mod coincidence {
         };
         let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut file0, None).expect("Could not create non periodic (photon) TDC reference.");
 
-        let mut ci = 0;
+        let mut stream = StreamState::new();
+        let mut sorter = TimeSorter::default();
         let mut file = fs::File::open(file)?;
         let mut buffer: Vec<u8> = vec![0; 512_000_000];
         let mut total_size = 0;
+        let mut ignored = IgnoredPackets::default();
         let start = Instant::now();
         
+        //Packets are time sorted. The ones held by the sorter are processed with the next chunk.
         while let Ok(size) = file.read(&mut buffer) {
-            if size == 0 {println!("Finished Reading."); break;}
+            let last = size == 0;
             total_size += size;
-            println!("MB Read: {}", total_size / 1_000_000 );
+            if last {println!("Finished Reading.");} else {println!("MB Read: {}", total_size / 1_000_000 );}
             let mut temp_edata = TempElectronData::new();
             let mut temp_tdc = TempTdcData::new();
             //let mut packet_chunks = buffer[0..size].chunks_exact(8);
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => {ci=nci;},
-                    _ => {
-                        let packet = Pack { chip_index: ci, data: packet_change(pack_oct)[0] };
-                        match packet.id() {
-                            6 if packet.tdc_type() == np_tdc.id() => {
-                                temp_tdc.add_tdc(&packet, 0);
-                            },
-                            6 if packet.tdc_type() == spim_tdc.id() => {
-                                coinc_data.add_spim_line(&packet);
-                            },
-                            11 => {
-                                let se = SingleElectron::new(&packet, coinc_data.spim_tdc);
-                                temp_edata.electron.add_electron(se);
-                            },
-                            _ => {}, //println!("{}", packet.tdc_type());},
-                        };
+            sorter.sorted(stream.packets::<Pack>(&buffer[0..size]), last).for_each(|packet| {
+                match packet {
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == np_tdc.id() => {
+                        temp_tdc.add_tdc(coinc_data.timeline.tdc_time_abs(&packet), 0);
+                    },
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == spim_tdc.id() => {
+                        coinc_data.add_spim_line(&packet);
+                    },
+                    StreamPacket::Tdc(packet) if coinc_data.is_spim_line_stop(packet.tdc_type()) => {
+                        coinc_data.add_spim_line_stop(&packet);
                     },
+                    StreamPacket::Electron(packet) => {
+                        let se = coinc_data.new_electron(&packet);
+                        temp_edata.electron.add_electron(se);
+                    },
+                    StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => {ignored.add(&packet);},
                 };
             });
-        coinc_data.add_events(temp_edata, &mut temp_tdc, 104, 40);
+        let (time_delay, time_width) = (coinc_data.time_delay, coinc_data.time_width);
+        coinc_data.add_events(temp_edata, &mut temp_tdc, time_delay, time_width);
         println!("Time elapsed: {:?}", start.elapsed());
-
+        if last {break;}
         }
         println!("Total number of bytes read {}", total_size);
+        println!("Ignored packets: {:?}. Packets out of order: {}.", ignored, sorter.late());
         Ok(())
     }
     
     pub fn correct_coincidence_isi(file1: &str, file2: &str, coinc_data: &mut ElectronData) -> (TempTdcData, usize) {
     
         //TP3 configurating TDC Ref
-        let mut file0 = fs::File::open(file1).unwrap();
+        let file0 = fs::File::open(file1).unwrap();
         let progress_size = file0.metadata().unwrap().len() as u64;
+        let mut file0 = TdcDiscovery::new(file0, Default::default());
         let spim_tdc = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut file0, Some(coinc_data.spim_size.1)).expect("Could not create period TDC reference.");
         coinc_data.prepare_spim(spim_tdc);
         let _begin_tp3_time = spim_tdc.begin_frame;
@@ -432,7 +526,7 @@ pub mod coincidence {
         let mut correct_vector = IsiBoxCorrectVector(vec![None; temp_tdc.get_vec_len()], 0);
         
         let mut offset = 0;
-        let mut ci = 0;
+        let mut stream = StreamState::new();
         let mut file = fs::File::open(file1).unwrap();
         let mut buffer: Vec<u8> = vec![0; ISI_BUFFER_SIZE];
         let mut total_size = 0;
@@ -444,48 +538,37 @@ pub mod coincidence {
             total_size += size;
             bar.inc(ISI_BUFFER_SIZE as u64);
             //println!("Reading for correction. MB Read: {}", total_size / 1_000_000 );
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => {ci=nci;},
-                    _ => {
-                        let packet = Pack { chip_index: ci, data: packet_change(pack_oct)[0] };
-                        match packet.id() {
-                            6 if packet.tdc_type() == spim_tdc.id() => {
-                                tp3_tdc_counter += 1;
-                                coinc_data.add_spim_line(&packet);
-                                let of = coinc_data.estimate_overflow(&packet).unwrap();
-                                let isi_val = tdc_iter.next().unwrap();
-                                let tdc_val = packet.tdc_time_abs() + of * Pack::tdc_overflow() * 6;
-                                
-                                //Sometimes the estimative time does not work, underestimating of.
-                                //This tries to recover it out.
-                                let t_dif = if isi_val.1 > tdc_val {
-                                    let of = of + 1;
-                                    let tdc_val = packet.tdc_time_abs() + of * Pack::tdc_overflow() * 6;
-                                    tdc_val - isi_val.1
-                                } else {
-                                    tdc_val - isi_val.1
-                                };
-                                
-                                
-                                if (offset != 0) && ((t_dif > offset + 1_000) || (offset > t_dif + 1_000)) {
-                                    println!("***IsiBox***: Possibly problem in acquiring TDC in both TP3 and IsiBox. Values for debug (Time difference, TDC, Isi, Packet_tdc, overflow, current offset) are: {} and {} and {} and {} and {} and {}", t_dif, tdc_val, isi_val.1, packet.tdc_time_abs(), of, offset);
-                                    quit = true;
-                                } else {
-                                    //Note here that a bad one will be skipped but the next one
-                                    //will try to fix it because the min_index of
-                                    //'IsiBoxCorrectorVector' won't be setted in the bad
-                                    //interaction.
-                                    correct_vector.add_offset(isi_val.0, t_dif);
-                                }
-
-                                offset = t_dif;
+            stream.packets::<Pack>(&buffer[0..size]).for_each(|packet| {
+                match packet {
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == spim_tdc.id() => {
+                        tp3_tdc_counter += 1;
+                        coinc_data.add_spim_line(&packet);
+                        let isi_val = tdc_iter.next().unwrap();
+                        let mut tdc_val = coinc_data.timeline.tdc_time_abs(&packet);
+                        //The TDC can be one overflow behind the IsiBox. This tries to recover it.
+                        if isi_val.1 > tdc_val {tdc_val += TimeBase::Tdc.overflow();}
+                        if isi_val.1 > tdc_val {
+                            println!("***IsiBox***: TDC is behind the IsiBox clock. Values for debug (TDC, Isi, Packet_tdc, current offset) are: {} and {} and {} and {}", tdc_val, isi_val.1, packet.tdc_time_abs(), offset);
+                            quit = true;
+                            return;
+                        }
+                        let t_dif = tdc_val - isi_val.1;
+
+                        if (offset != 0) && ((t_dif > offset + 1_000) || (offset > t_dif + 1_000)) {
+                            println!("***IsiBox***: Possibly problem in acquiring TDC in both TP3 and IsiBox. Values for debug (Time difference, TDC, Isi, Packet_tdc, current offset) are: {} and {} and {} and {} and {}", t_dif, tdc_val, isi_val.1, packet.tdc_time_abs(), offset);
+                            quit = true;
+                        } else {
+                            //Note here that a bad one will be skipped but the next one
+                            //will try to fix it because the min_index of
+                            //'IsiBoxCorrectorVector' won't be setted in the bad
+                            //interaction.
+                            correct_vector.add_offset(isi_val.0, t_dif);
+                        }
+
+                        offset = t_dif;
                      
-                            },
-                            11 => {},
-                            _ => {}, //println!("{}", packet.tdc_type());},
-                        };
                     },
+                    _ => {},
                 };
             });
         temp_tdc.correct_tdc(&mut correct_vector);
@@ -498,17 +581,19 @@ pub mod coincidence {
     pub fn search_coincidence_isi(file1: &str, file2: &str, coinc_data: &mut ElectronData) -> io::Result<()> {
     
         //TP3 configurating TDC Ref
-        let mut file0 = fs::File::open(file1)?;
+        let file0 = fs::File::open(file1)?;
         let progress_size = file0.metadata().unwrap().len() as u64;
+        let mut file0 = TdcDiscovery::new(file0, Default::default());
         let spim_tdc = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut file0, Some(coinc_data.spim_size.1)).expect("Could not create period TDC reference.");
         coinc_data.prepare_spim(spim_tdc);
     
         let (mut temp_tdc, max_total_size) = correct_coincidence_isi(file1, file2, coinc_data);
         
-        let mut ci = 0;
+        let mut stream = StreamState::new();
         let mut file = fs::File::open(file1)?;
         let mut buffer: Vec<u8> = vec![0; ISI_BUFFER_SIZE];
         let mut total_size = 0;
+        let mut ignored = IgnoredPackets::default();
         
         let bar = ProgressBar::new(progress_size);
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.white/black} {percent}% {pos:>7}/{len:7} [ETA: {eta}] Searching electron photon coincidences")
@@ -523,29 +608,80 @@ pub mod coincidence {
             //println!("MB Read: {}", total_size / 1_000_000 );
             //if (total_size / 1_000_000) > 10_000 {break;}
             let mut temp_edata = TempElectronData::new();
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => {ci=nci;},
-                    _ => {
-                        let packet = Pack { chip_index: ci, data: packet_change(pack_oct)[0] };
-                        match packet.id() {
-                            6 if packet.tdc_type() == spim_tdc.id() => {
-                                coinc_data.add_spim_line(&packet);
-                            },
-                            11 => {
-                                let se = SingleElectron::new(&packet, coinc_data.spim_tdc);
-                                temp_edata.electron.add_electron(se);
-                            },
-                            _ => {}, //println!("{}", packet.tdc_type());},
-                        };
+            stream.packets::<Pack>(&buffer[0..size]).for_each(|packet| {
+                match packet {
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == spim_tdc.id() => {
+                        coinc_data.add_spim_line(&packet);
+                    },
+                    StreamPacket::Tdc(packet) if coinc_data.is_spim_line_stop(packet.tdc_type()) => {
+                        coinc_data.add_spim_line_stop(&packet);
                     },
+                    StreamPacket::Electron(packet) => {
+                        let se = coinc_data.new_electron(&packet);
+                        temp_edata.electron.add_electron(se);
+                    },
+                    StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => {ignored.add(&packet);},
                 };
             });
         coinc_data.add_events(temp_edata, &mut temp_tdc, 83, 20);
         }
         println!("***IsiBox***: Coincidence search is over.");
+        println!("***IsiBox***: Ignored packets: {:?}.", ignored);
         Ok(())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const DELAY: f32 = 150.0;
+        const OFFSETS: [(POSITION, POSITION, f32); 4] = [(10, 20, 2.0), (300, 40, -3.0), (600, 100, 4.0), (900, 200, 0.0)];
+
+        fn walk(tot: u16) -> f32 {
+            40.0 / (1.0 + tot as f32 / 4.0)
+        }
+
+        ///Coincidences of pixels with known offsets and time walk, as found by a search already
+        ///corrected by `applied`. A few clustered events have a ToT sum above `TOT_RANGE`.
+        fn coincidences(applied: &TimeCalibration) -> ElectronData {
+            let args = ["", "file", "0", "1", "1", "1"].map(String::from);
+            let mut data = ElectronData::new(&ConfigAcquisition::new(&args).unwrap());
+            let fine = TimeBase::Tdc.tick() as f32;
+            for event in 0..12 {
+                let jitter = (event % 5) as f32 * 0.2 - 0.4;
+                for &(x, y, offset) in OFFSETS.iter() {
+                    for tot in (1..60).chain([1500]) {
+                        let dt = DELAY + walk(tot.min(TOT_RANGE as u16 - 1)) + offset + jitter - applied.correction(x, y, tot);
+                        data.rel_time.push((dt / fine).round() as i64);
+                        data.x.push(x);
+                        data.y.push(y);
+                        data.tot.push(tot);
+                    }
+                }
+            }
+            data
+        }
+
+        fn assert_recovered(calibration: &TimeCalibration) {
+            for &(x, y, offset) in OFFSETS.iter() {
+                for tot in 1..60 {
+                    let expected = DELAY + walk(tot) + offset;
+                    let found = calibration.correction(x, y, tot) - calibration.coincidence_delay;
+                    assert!((found - expected).abs() < 0.5, "Pixel ({}, {}) with ToT {}: {} ns instead of {} ns.", x, y, tot, found, expected);
+                }
+            }
+            assert!(calibration.coincidence_width < 2.0);
+        }
+
+        #[test]
+        fn time_calibration_recovers_offsets_and_walk() {
+            let first = coincidences(&TimeCalibration::empty()).refine_time_calibration(TimeCalibration::empty());
+            assert_recovered(&first);
+
+            let refined = coincidences(&first).refine_time_calibration(first.clone());
+            assert_recovered(&refined);
+        }
+    }
 }
 
 pub mod isi_box {
@@ -994,8 +1130,9 @@ pub mod isi_box {
 
 pub mod ntime_resolved {
     use std::fs::OpenOptions;
-    use crate::packetlib::{Packet, PacketEELS as Pack, packet_change};
-    use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef};
+    use crate::packetlib::{Packet, PacketEELS as Pack, IgnoredPackets, StreamState, StreamPacket};
+    use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef, TdcDiscovery};
+    use crate::timelib::Timeline;
     use std::io::prelude::*;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron};
     use std::convert::TryInto;
@@ -1020,9 +1157,11 @@ pub mod ntime_resolved {
         spimx: POSITION, //The horinzontal axis of the spim,
         spimy: POSITION, //The vertical axis of the spim,
         tdc_periodic: Option<PeriodicTdcRef>, //The periodic tdc. Can be none if xspim and yspim <= 1,
+        timeline: Timeline, //Absolute electron and tdc times,
         spim_tdc_type: TdcType, //The tdc type for the spim,
         extra_tdc_type: TdcType, //The tdc type for the external,
         remove_clusters: bool,
+        energy_window: Option<(f32, f32)>, //Energy window of the electrons kept, in keV,
     }
 
     fn as_bytes<T>(v: &[T]) -> &[u8] {
@@ -1037,7 +1176,7 @@ pub mod ntime_resolved {
         fn prepare(&mut self, file: &mut fs::File) {
             self.tdc_periodic = match self.tdc_periodic {
                 None if self.spimx>1 && self.spimy>1 => {
-                    Some(PeriodicTdcRef::new(self.spim_tdc_type.clone(), file, Some(self.spimy)).expect("Problem in creating periodic tdc ref."))
+                    Some(PeriodicTdcRef::new(self.spim_tdc_type, &mut TdcDiscovery::new(file, Default::default()), Some(self.spimy)).expect("Problem in creating periodic tdc ref."))
                 },
                 Some(val) => Some(val),
                 _ => None,
@@ -1045,28 +1184,30 @@ pub mod ntime_resolved {
         }
 
         fn add_electron(&mut self, packet: &Pack) {
-            let se = SingleElectron::new(packet, self.tdc_periodic);
+            let se = SingleElectron::new(packet, self.timeline.electron_time(packet), self.tdc_periodic);
             self.ensemble.add_electron(se);
         }
 
         fn add_spim_tdc(&mut self, packet: &Pack) {
             //Synchronizing clocks using two different approaches. It is always better to use a multiple of 2 and use the FPGA counter.
+            let time = self.timeline.tdc_time(packet);
             match &mut self.tdc_periodic {
                 //Some(my_tdc_periodic) if packet.tdc_type() == self.tdc_type.associate_value() => {
                 Some(my_tdc_periodic) => {
-                    my_tdc_periodic.upt(packet.tdc_time_norm(), packet.tdc_counter());
+                    my_tdc_periodic.upt(time, packet.tdc_counter());
                 },
                 _ => {},
             };
         }
         
         fn add_extra_tdc(&mut self, _packet: &Pack) {
-            //self.spectra.push(SPIM_PIXELS);
+            //self.spectra.push(spim_pixels());
             //spimlib::get_spimindex(, dt: TIME, spim_tdc: &PeriodicTdcRef, self.spimx, self.spimy;
         }
 
         fn process(&mut self) -> Result<(), ErrorType> {
             if self.ensemble.try_clean(0, self.remove_clusters) {
+                if let Some((min, max)) = self.energy_window {self.ensemble.retain_energy(min, max);}
                 for val in self.ensemble.values() {
                     if let Some(index) = val.get_or_not_spim_index(self.tdc_periodic, self.spimx, self.spimy) {
                         self.spectra.push(index);
@@ -1099,9 +1240,11 @@ pub mod ntime_resolved {
                 spimx: my_config.xspim,
                 spimy: my_config.yspim,
                 tdc_periodic: None,
+                timeline: Timeline::default(),
                 spim_tdc_type: TdcType::TdcOneFallingEdge,
                 extra_tdc_type: TdcType::TdcTwoRisingEdge,
                 remove_clusters: my_config.remove_cluster,
+                energy_window: my_config.energy_window,
             })
         }
     }
@@ -1115,34 +1258,118 @@ pub mod ntime_resolved {
         let mut buffer: Vec<u8> = vec![0; 1_000_000_000];
         
         let mut total_size = 0;
-        let mut ci = 0;
+        let mut stream = StreamState::new();
+        let mut ignored = IgnoredPackets::default();
 
         while let Ok(size) = my_file.read(&mut buffer) {
             if size==0 {break;}
             total_size += size;
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match pack_oct {
-                    &[84, 80, 88, 51, nci, _, _, _] => {ci = nci},
-                    _ => {
-                        let packet = Pack{chip_index: ci, data: packet_change(pack_oct)[0]};
-                        match packet.id() {
-                            6 if packet.tdc_type() == data.spim_tdc_type.associate_value() => {
-                                data.add_spim_tdc(&packet);
-                            },
-                            6 if packet.tdc_type() == data.extra_tdc_type.associate_value() => {
-                                data.add_extra_tdc(&packet);
-                            },
-                            11 => {
-                                data.add_electron(&packet);
-                            },
-                            _ => {},
-                        };
+            stream.packets::<Pack>(&buffer[0..size]).for_each(|packet| {
+                match packet {
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == data.spim_tdc_type.associate_value() => {
+                        data.add_spim_tdc(&packet);
                     },
+                    StreamPacket::Tdc(packet) if packet.tdc_type() == data.extra_tdc_type.associate_value() => {
+                        data.add_extra_tdc(&packet);
+                    },
+                    StreamPacket::Electron(packet) => {
+                        data.add_electron(&packet);
+                    },
+                    StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => {ignored.add(&packet);},
                 };
             });
             data.process().expect("Error in processing");
             println!("File: {:?}. Total number of bytes read (MB): ~ {}", file, total_size/1_000_000);
+            println!("Ignored packets: {:?}.", ignored);
             println!("Time elapsed: {:?}", start.elapsed());
         };
     }
 }
+
+pub mod tot_spectra {
+    use crate::packetlib::{Packet, PacketEELS as Pack, IgnoredPackets, StreamState, StreamPacket};
+    use crate::layoutlib::layout;
+    use crate::auxiliar::value_types::*;
+    use std::fs::OpenOptions;
+    use std::io::prelude::*;
+    use std::time::Instant;
+    use std::{fs, io};
+
+    fn as_bytes<T>(v: &[T]) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                v.as_ptr() as *const u8,
+                std::mem::size_of_val(v))
+        }
+    }
+
+    ///ToT histogram of each pixel, used to fit the energy calibration. ToT above `bins` are counted
+    ///in the last bin.
+    pub struct TotHistograms {
+        width: POSITION,
+        height: POSITION,
+        bins: usize,
+        counts: Vec<u32>,
+    }
+
+    impl TotHistograms {
+        pub fn new(bins: usize) -> Self {
+            let (width, height) = (layout().width(), layout().height());
+            Self {
+                width,
+                height,
+                bins,
+                counts: vec![0; width as usize * height as usize * bins],
+            }
+        }
+
+        #[inline]
+        fn add_electron(&mut self, packet: &Pack) {
+            let pixel = (packet.x() + self.width * packet.y()) as usize;
+            let bin = (packet.tot() as usize).min(self.bins - 1);
+            self.counts[pixel * self.bins + bin] += 1;
+        }
+
+        ///Histogram of the pixel (`x`, `y`).
+        pub fn pixel(&self, x: POSITION, y: POSITION) -> &[u32] {
+            let pixel = (x + self.width * y) as usize;
+            &self.counts[pixel * self.bins..(pixel + 1) * self.bins]
+        }
+
+        ///Writes the counts as u32, with shape (height, width, bins).
+        pub fn output(&self, name: &str) {
+            let mut tfile = OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .create(true)
+                .open(name).expect("Could not output ToT histograms.");
+            tfile.write_all(as_bytes(&self.counts)).expect("Could not write ToT histograms to file.");
+            println!("Outputting ToT histograms under {:?} name. Shape is ({}, {}, {}).", name, self.height, self.width, self.bins);
+        }
+    }
+
+    pub fn build_tot_histograms(file: &str, bins: usize) -> io::Result<TotHistograms> {
+        let mut histograms = TotHistograms::new(bins);
+        let start = Instant::now();
+        let mut my_file = fs::File::open(file)?;
+        let mut buffer: Vec<u8> = vec![0; 512_000_000];
+        let mut total_size = 0;
+        let mut stream = StreamState::new();
+        let mut ignored = IgnoredPackets::default();
+
+        while let Ok(size) = my_file.read(&mut buffer) {
+            if size == 0 {break;}
+            total_size += size;
+            stream.packets::<Pack>(&buffer[0..size]).for_each(|packet| {
+                match packet {
+                    StreamPacket::Electron(packet) => histograms.add_electron(&packet),
+                    StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => {ignored.add(&packet);},
+                };
+            });
+            println!("File: {:?}. Total number of bytes read (MB): ~ {}", file, total_size/1_000_000);
+        }
+        println!("Ignored packets: {:?}.", ignored);
+        println!("Time elapsed: {:?}", start.elapsed());
+        Ok(histograms)
+    }
+}
@@ -0,0 +1,127 @@
+//!`recordlib` tees the raw Timepix3 stream of a live measurement to disk. Packets are written
+//!untouched to rotating `.tpx3` files, next to a `settings.json` sidecar, so the session can be
+//!reprocessed later with `postlib`.
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::{Settings, misc::TimepixRead};
+use chrono::prelude::*;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+const HEADER: &[u8] = b"TPX3";
+
+///Contents of the sidecar file. The TDC references are those of `settings`.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    started: &'a str,
+    settings: &'a Settings,
+    files: &'a [String],
+    bytes: u64,
+}
+
+///Reads from `R` and writes every byte read to `rawXXXXXX.tpx3` files in a new folder. A file is
+///closed once it holds `max_file_size` bytes, at the next chip header, so each file can be read alone.
+///A failure to write stops the recording but not the measurement.
+pub struct Recorder<R> {
+    inner: R,
+    folder: PathBuf,
+    started: String,
+    settings: Settings,
+    files: Vec<String>,
+    file: Option<File>,
+    file_size: u64,
+    max_file_size: u64,
+    bytes: u64,
+}
+
+impl<R> Recorder<R> {
+    fn rotate(&mut self) -> io::Result<()> {
+        let name = format!("raw{:06}.tpx3", self.files.len());
+        self.file = Some(File::create(self.folder.join(&name))?);
+        self.file_size = 0;
+        self.files.push(name);
+        self.write_sidecar()
+    }
+
+    fn write_sidecar(&self) -> io::Result<()> {
+        let sidecar = Sidecar {
+            started: &self.started,
+            settings: &self.settings,
+            files: &self.files,
+            bytes: self.bytes,
+        };
+        let file = File::create(self.folder.join("settings.json"))?;
+        serde_json::to_writer_pretty(file, &sidecar).map_err(io::Error::from)
+    }
+}
+
+impl<R: Read> Recorder<R> {
+    ///Creates a timestamped folder inside `dir` holding the recording.
+    pub fn new(inner: R, dir: &str, settings: &Settings, max_file_size: u64) -> Result<Self, Tp3ErrorKind> {
+        let now = Local::now();
+        let folder = PathBuf::from(dir).join(now.format("%Y%m%d_%H%M%S").to_string());
+        fs::create_dir_all(&folder).map_err(|_| Tp3ErrorKind::SetNoWriteFile)?;
+        let mut recorder = Recorder {
+            inner,
+            folder,
+            started: now.to_rfc3339(),
+            settings: *settings,
+            files: Vec::new(),
+            file: None,
+            file_size: 0,
+            max_file_size,
+            bytes: 0,
+        };
+        recorder.rotate().map_err(|_| Tp3ErrorKind::SetNoWriteFile)?;
+        println!("Recording raw data in {:?}.", recorder.folder);
+        Ok(recorder)
+    }
+
+    ///Offset in `data` of the first chip header aligned on a packet.
+    fn next_header(&self, data: &[u8]) -> Option<usize> {
+        let first = ((8 - self.bytes % 8) % 8) as usize;
+        (first..data.len())
+            .step_by(8)
+            .find(|&index| data[index..].starts_with(HEADER))
+    }
+
+    fn record(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let size = match self.file_size >= self.max_file_size {
+                true => match self.next_header(data) {
+                    Some(0) => {self.rotate()?; continue;},
+                    Some(index) => index,
+                    None => data.len(),
+                },
+                false => data.len(),
+            };
+            if let Some(file) = self.file.as_mut() {
+                file.write_all(&data[..size])?;
+            }
+            self.file_size += size as u64;
+            self.bytes += size as u64;
+            data = &data[size..];
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        if self.file.is_some() && self.record(&buf[..size]).is_err() {
+            println!("Could not write the raw data. Recording is stopped.");
+            self.file = None;
+        }
+        Ok(size)
+    }
+}
+
+impl<R: Read> TimepixRead for Recorder<R> {}
+
+impl<R> Drop for Recorder<R> {
+    fn drop(&mut self) {
+        if self.write_sidecar().is_err() {println!("Could not write the recording sidecar.");}
+    }
+}
@@ -0,0 +1,511 @@
+//!`simulatelib` generates synthetic TPX3 streams: electrons of a (scanned) beam, the TDC edges of the
+//!scan lines and photons detected on a TDC line. A `Simulation` is configured by `SimulationBuilder` and
+//!is written to a `.tpx3` file or read as a `TimepixRead` stream, so demos and tests share one generator.
+use crate::errorlib::Tp3ErrorKind;
+use crate::packetlib::InversePacket;
+use crate::tdclib::TdcType;
+use crate::layoutlib::layout;
+use crate::calibrationlib::TOT_RANGE;
+use crate::auxiliar::misc::TimepixRead;
+use crate::auxiliar::value_types::*;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp, Poisson, StandardNormal};
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+///Electrons per ns for a beam current of 1 pA.
+const ELECTRONS_PER_PA: f64 = 6.25e-3;
+///Length of a generated block when there is no scan, in ns.
+const BLOCK_TIME: f64 = 1_000_000.0;
+
+///A gaussian peak along the dispersive axis (x), in detector pixels.
+#[derive(Copy, Clone, Debug)]
+pub struct Peak {
+    pub weight: f64,
+    pub center: f64,
+    pub sigma: f64,
+    ///Probability that an electron of this peak emits a detected photon.
+    pub photon_yield: f64,
+}
+
+impl Peak {
+    pub fn new(weight: f64, center: f64, sigma: f64) -> Self {
+        Peak { weight, center, sigma, photon_yield: 0.0 }
+    }
+
+    pub fn with_photon_yield(mut self, photon_yield: f64) -> Self {
+        self.photon_yield = photon_yield;
+        self
+    }
+}
+
+///Spectrum on the detector: peaks along x and a gaussian profile along y.
+#[derive(Clone, Debug)]
+pub struct Spectrum {
+    peaks: Vec<Peak>,
+    y_center: f64,
+    y_sigma: f64,
+}
+
+impl Spectrum {
+    pub fn new(y_center: f64, y_sigma: f64) -> Self {
+        Spectrum { peaks: Vec::new(), y_center, y_sigma }
+    }
+
+    pub fn peak(mut self, peak: Peak) -> Self {
+        self.peaks.push(peak);
+        self
+    }
+
+    ///Position of an electron and the photon yield of its peak.
+    fn sample<R: Rng>(&self, rng: &mut R) -> (f64, f64, f64) {
+        let total = self.peaks.iter().map(|peak| peak.weight).sum::<f64>();
+        let mut pick = rng.gen::<f64>() * total;
+        let peak = self.peaks.iter().find(|peak| {pick -= peak.weight; pick < 0.0}).unwrap_or(&self.peaks[self.peaks.len() - 1]);
+        let x = peak.center + peak.sigma * rng.sample::<f64, _>(StandardNormal);
+        let y = self.y_center + self.y_sigma * rng.sample::<f64, _>(StandardNormal);
+        (x, y, peak.photon_yield)
+    }
+}
+
+impl Default for Spectrum {
+    ///A zero-loss peak at the column 128 of the row 128.
+    fn default() -> Self {
+        Spectrum::new(128.0, 0.0).peak(Peak::new(1.0, 128.0, 3.0))
+    }
+}
+
+///Scan of the beam. Each line is a pulse on the line TDC. Times are in ns.
+#[derive(Copy, Clone, Debug)]
+pub struct ScanGeometry {
+    pub xscan: POSITION,
+    pub yscan: POSITION,
+    pub pixel_time: f64,
+    pub flyback: f64,
+}
+
+///ToT of the first hit of an electron.
+#[derive(Copy, Clone, Debug)]
+pub enum TotDistribution {
+    Fixed(u16),
+    Uniform { min: u16, max: u16 },
+    Normal { mean: f64, sigma: f64 },
+}
+
+impl TotDistribution {
+    ///ToT values are in [1, `TOT_RANGE` - 1].
+    fn is_valid(&self) -> bool {
+        let max_tot = TOT_RANGE as u16 - 1;
+        match *self {
+            TotDistribution::Fixed(tot) => (1..=max_tot).contains(&tot),
+            TotDistribution::Uniform { min, max } => 1 <= min && min <= max && max <= max_tot,
+            TotDistribution::Normal { mean, sigma } => mean.is_finite() && sigma.is_finite() && sigma >= 0.0,
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u16 {
+        match *self {
+            TotDistribution::Fixed(tot) => tot,
+            TotDistribution::Uniform { min, max } => rng.gen_range(min..=max),
+            TotDistribution::Normal { mean, sigma } => (mean + sigma * rng.sample::<f64, _>(StandardNormal)).round().clamp(1.0, 1023.0) as u16,
+        }
+    }
+}
+
+///Extra hits of an electron around its first hit.
+#[derive(Copy, Clone, Debug)]
+pub struct ClusterSpread {
+    ///Mean number of extra hits.
+    pub mean_hits: f64,
+    ///Largest distance to the first hit, in pixels.
+    pub radius: i64,
+    ///Largest delay after the first hit, in ns.
+    pub delay: f64,
+    ///ToT of an extra hit relative to the first one.
+    pub tot_fraction: f64,
+}
+
+///Detection of the photons. A photon is a pulse on a TDC line: `edge` when it is detected and the
+///opposite edge `width` later. Times are in ns.
+#[derive(Copy, Clone, Debug)]
+pub struct PhotonEmission {
+    ///Decay time of the emission.
+    pub lifetime: f64,
+    ///Standard deviation of the detection time.
+    pub jitter: f64,
+    ///Delay of the detection chain.
+    pub delay: f64,
+    pub width: f64,
+    pub edge: TdcType,
+}
+
+impl Default for PhotonEmission {
+    fn default() -> Self {
+        PhotonEmission { lifetime: 1.0, jitter: 0.0, delay: 0.0, width: 10.0, edge: TdcType::TdcTwoRisingEdge }
+    }
+}
+
+///Counts of a simulation.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SimulationSummary {
+    pub electrons: u64,
+    ///Hits written, cluster hits included.
+    pub hits: u64,
+    ///Hits outside the chips, not written.
+    pub lost_hits: u64,
+    pub photons: u64,
+    ///TDC packets written, both edges included.
+    pub tdcs: u64,
+    ///Simulated time, in ns.
+    pub duration: f64,
+}
+
+type Region = Box<dyn Fn(POSITION, POSITION) -> bool + Send>;
+
+///Configuration of a `Simulation`. Without a scan, the beam stays still for `duration`.
+pub struct SimulationBuilder {
+    scan: Option<ScanGeometry>,
+    frames: COUNTER,
+    duration: Option<f64>,
+    current: f64,
+    spectrum: Spectrum,
+    regions: Vec<(Region, Spectrum)>,
+    tot: TotDistribution,
+    cluster: Option<ClusterSpread>,
+    photons: Option<PhotonEmission>,
+    line_edge: TdcType,
+    seed: Option<u64>,
+}
+
+impl Default for SimulationBuilder {
+    fn default() -> Self {
+        SimulationBuilder {
+            scan: None,
+            frames: 1,
+            duration: None,
+            current: 1.0,
+            spectrum: Spectrum::default(),
+            regions: Vec::new(),
+            tot: TotDistribution::Fixed(1023),
+            cluster: None,
+            photons: None,
+            line_edge: TdcType::TdcOneFallingEdge,
+            seed: None,
+        }
+    }
+}
+
+impl SimulationBuilder {
+    pub fn new() -> Self {
+        SimulationBuilder::default()
+    }
+
+    ///Scans `xscan` x `yscan` pixels. Times are in ns.
+    pub fn scan(mut self, xscan: POSITION, yscan: POSITION, pixel_time: f64, flyback: f64) -> Self {
+        self.scan = Some(ScanGeometry { xscan, yscan, pixel_time, flyback });
+        self
+    }
+
+    ///Number of scanned frames.
+    pub fn frames(mut self, frames: COUNTER) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    ///Simulated time, in ns. With a scan, the simulation ends at the first line after it.
+    pub fn duration(mut self, duration: f64) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    ///Beam current, in pA.
+    pub fn beam_current(mut self, current: f64) -> Self {
+        self.current = current;
+        self
+    }
+
+    pub fn spectrum(mut self, spectrum: Spectrum) -> Self {
+        self.spectrum = spectrum;
+        self
+    }
+
+    ///Spectrum of the scan positions (x, y) where `region` is true. The first matching region is used.
+    pub fn spectrum_where<F: 'static + Fn(POSITION, POSITION) -> bool + Send>(mut self, region: F, spectrum: Spectrum) -> Self {
+        self.regions.push((Box::new(region), spectrum));
+        self
+    }
+
+    pub fn tot(mut self, tot: TotDistribution) -> Self {
+        self.tot = tot;
+        self
+    }
+
+    pub fn cluster(mut self, cluster: ClusterSpread) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    pub fn photons(mut self, photons: PhotonEmission) -> Self {
+        self.photons = Some(photons);
+        self
+    }
+
+    ///Edge starting each line. The opposite edge ends it.
+    pub fn line_tdc(mut self, edge: TdcType) -> Self {
+        self.line_edge = edge;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    fn spectrum_at(&self, x: POSITION, y: POSITION) -> &Spectrum {
+        self.regions.iter()
+            .find(|(region, _)| region(x, y))
+            .map_or(&self.spectrum, |(_, spectrum)| spectrum)
+    }
+
+    pub fn build(self) -> Result<Simulation, Tp3ErrorKind> {
+        let spectra_ok = std::iter::once(&self.spectrum).chain(self.regions.iter().map(|(_, spectrum)| spectrum))
+            .all(|spectrum| !spectrum.peaks.is_empty() && spectrum.peaks.iter().all(|peak| peak.weight >= 0.0 && peak.sigma >= 0.0) && spectrum.y_sigma >= 0.0);
+        let scan_ok = match self.scan {
+            Some(scan) => scan.xscan > 0 && scan.yscan > 0 && scan.pixel_time > 0.0 && scan.flyback >= 0.0,
+            None => self.duration.is_some(),
+        };
+        let photons_ok = self.photons.is_none_or(|photons| photons.lifetime > 0.0 && photons.jitter >= 0.0 && photons.width > 0.0 && photons.edge != TdcType::NoTdc);
+        if !(spectra_ok && scan_ok && photons_ok && self.tot.is_valid() && self.current >= 0.0 && self.line_edge != TdcType::NoTdc) {
+            return Err(Tp3ErrorKind::SimulationBadSettings);
+        }
+        let rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(Simulation {
+            config: self,
+            rng,
+            time: 0.0,
+            frame: 0,
+            line: 0,
+            tdc_counters: [0; 3],
+            summary: SimulationSummary::default(),
+        })
+    }
+}
+
+///A configured stream. Packets are generated one line (or one block, without a scan) at a time and
+///are sorted by time within it.
+pub struct Simulation {
+    config: SimulationBuilder,
+    rng: StdRng,
+    time: f64,
+    frame: COUNTER,
+    line: POSITION,
+    ///Trigger counters of the input lines. As in SPIDR, both edges of a line increment its counter.
+    tdc_counters: [usize; 3],
+    summary: SimulationSummary,
+}
+
+impl Simulation {
+    pub fn summary(&self) -> SimulationSummary {
+        self.summary
+    }
+
+    fn is_over(&self) -> bool {
+        let out_of_time = self.config.duration.is_some_and(|duration| self.time >= duration);
+        let out_of_frames = self.config.scan.is_some() && self.frame >= self.config.frames;
+        out_of_time || out_of_frames
+    }
+
+    fn add_tdc(&mut self, packets: &mut Vec<(usize, [u8; 16])>, time: f64, edge: TdcType) {
+        let time = time.round() as usize;
+        let counter = &mut self.tdc_counters[edge.input_line()];
+        *counter += 1;
+        packets.push((time, InversePacket::new_inverse_tdc(time).create_tdc_array(*counter, edge)));
+        self.summary.tdcs += 1;
+    }
+
+    fn add_hit(&mut self, packets: &mut Vec<(usize, [u8; 16])>, x: f64, y: f64, time: f64, tot: u16) {
+        let (x, y) = (x.round(), y.round());
+        let on_chip = x >= 0.0 && y >= 0.0 && layout().raw_position(x as POSITION, y as POSITION).is_some();
+        if !on_chip {
+            self.summary.lost_hits += 1;
+            return;
+        }
+        let time = time.round() as usize;
+        packets.push((time, InversePacket::new_inverse_electron(x as usize, y as usize, time).with_tot(tot as usize).create_electron_array()));
+        self.summary.hits += 1;
+    }
+
+    ///An electron at `time`, with its cluster and its photon.
+    fn add_electron(&mut self, packets: &mut Vec<(usize, [u8; 16])>, time: f64, scan_position: (POSITION, POSITION)) {
+        let (x, y, photon_yield) = self.config.spectrum_at(scan_position.0, scan_position.1).sample(&mut self.rng);
+        let tot = self.config.tot.sample(&mut self.rng);
+        self.summary.electrons += 1;
+        self.add_hit(packets, x, y, time, tot);
+        if let Some(cluster) = self.config.cluster {
+            for _ in 0..poisson(&mut self.rng, cluster.mean_hits) {
+                let dx = self.rng.gen_range(-cluster.radius..=cluster.radius) as f64;
+                let dy = self.rng.gen_range(-cluster.radius..=cluster.radius) as f64;
+                let delay = self.rng.gen::<f64>() * cluster.delay;
+                let extra_tot = ((tot as f64 * cluster.tot_fraction).round() as u16).max(1);
+                self.add_hit(packets, x + dx, y + dy, time + delay, extra_tot);
+            }
+        }
+        if let Some(photons) = self.config.photons {
+            if self.rng.gen::<f64>() < photon_yield {
+                let decay = Exp::new(1.0 / photons.lifetime).unwrap().sample(&mut self.rng);
+                let jitter = photons.jitter * self.rng.sample::<f64, _>(StandardNormal);
+                let detection = (time + decay + photons.delay + jitter).max(0.0);
+                self.add_tdc(packets, detection, photons.edge);
+                self.add_tdc(packets, detection + photons.width, photons.edge.opposite_edge());
+                self.summary.photons += 1;
+            }
+        }
+    }
+
+    ///Packets of the next line, or of the next block without a scan. None once the simulation is over.
+    pub fn next_block(&mut self) -> Option<Vec<u8>> {
+        if self.is_over() {return None;}
+        let rate = self.config.current * ELECTRONS_PER_PA;
+        let mut packets = Vec::new();
+        match self.config.scan {
+            Some(scan) => {
+                let line_time = scan.xscan as f64 * scan.pixel_time;
+                self.add_tdc(&mut packets, self.time, self.config.line_edge);
+                for x in 0..scan.xscan {
+                    let start = self.time + x as f64 * scan.pixel_time;
+                    for _ in 0..poisson(&mut self.rng, rate * scan.pixel_time) {
+                        let time = start + self.rng.gen::<f64>() * scan.pixel_time;
+                        self.add_electron(&mut packets, time, (x, self.line));
+                    }
+                }
+                self.add_tdc(&mut packets, self.time + line_time, self.config.line_edge.opposite_edge());
+                self.time += line_time + scan.flyback;
+                self.line += 1;
+                if self.line == scan.yscan {
+                    self.line = 0;
+                    self.frame += 1;
+                }
+            },
+            None => {
+                let block = self.config.duration.map_or(BLOCK_TIME, |duration| BLOCK_TIME.min(duration - self.time));
+                for _ in 0..poisson(&mut self.rng, rate * block) {
+                    let time = self.time + self.rng.gen::<f64>() * block;
+                    self.add_electron(&mut packets, time, (0, 0));
+                }
+                self.time += block;
+            },
+        }
+        self.summary.duration = self.time;
+        packets.sort_by_key(|(time, _)| *time);
+        Some(packets.iter().flat_map(|(_, packet)| packet.iter().copied()).collect())
+    }
+
+    pub fn write_to<W: Write>(mut self, writer: &mut W) -> io::Result<SimulationSummary> {
+        while let Some(block) = self.next_block() {
+            writer.write_all(&block)?;
+        }
+        writer.flush()?;
+        Ok(self.summary)
+    }
+
+    pub fn write_file(self, path: &str) -> Result<SimulationSummary, Tp3ErrorKind> {
+        let mut file = File::create(path).map_err(|_| Tp3ErrorKind::SetNoWriteFile)?;
+        self.write_to(&mut file).map_err(|_| Tp3ErrorKind::SetNoWriteFile)
+    }
+
+    ///The simulation as a stream, to be used in place of the detector socket or of a file.
+    pub fn stream(self) -> SimulatedStream {
+        SimulatedStream { simulation: self, buffer: Vec::new(), position: 0 }
+    }
+}
+
+///`Read` end of a `Simulation`.
+pub struct SimulatedStream {
+    simulation: Simulation,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl SimulatedStream {
+    pub fn summary(&self) -> SimulationSummary {
+        self.simulation.summary()
+    }
+}
+
+impl Read for SimulatedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.simulation.next_block() {
+                Some(block) => {
+                    self.buffer = block;
+                    self.position = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.buffer.len() - self.position);
+        buf[..size].copy_from_slice(&self.buffer[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+impl TimepixRead for SimulatedStream {}
+
+fn poisson<R: Rng>(rng: &mut R, mean: f64) -> u64 {
+    match Poisson::new(mean) {
+        Ok(distribution) => distribution.sample(rng) as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packetlib::{Packet, PacketEELS, StreamState, StreamPacket};
+
+    #[test]
+    fn scan_decodes_back() {
+        let simulation = SimulationBuilder::new()
+            .scan(16, 8, 1_000.0, 500.0)
+            .frames(2)
+            .beam_current(10.0)
+            .spectrum(Spectrum::new(100.0, 0.0).peak(Peak::new(1.0, 300.0, 0.0).with_photon_yield(0.5)))
+            .tot(TotDistribution::Fixed(77))
+            .photons(PhotonEmission::default())
+            .seed(7)
+            .build()
+            .unwrap();
+        let mut stream = simulation.stream();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        let summary = stream.summary();
+
+        let (mut electrons, mut line_edges, mut photon_edges) = (0, 0, 0);
+        StreamState::new().packets::<PacketEELS>(&data).for_each(|packet| match packet {
+            StreamPacket::Electron(packet) => {
+                assert_eq!((packet.x(), packet.y(), packet.tot()), (300, 100, 77));
+                electrons += 1;
+            },
+            StreamPacket::Tdc(packet) if packet.tdc_type() == TdcType::TdcOneFallingEdge.associate_value() || packet.tdc_type() == TdcType::TdcOneRisingEdge.associate_value() => line_edges += 1,
+            StreamPacket::Tdc(_) => photon_edges += 1,
+            StreamPacket::Other(_) => panic!("Unexpected packet."),
+        });
+        assert_eq!(electrons, summary.hits);
+        assert_eq!(line_edges, 2 * 8 * 2);
+        assert_eq!(photon_edges, 2 * summary.photons);
+        assert!(summary.photons > 0 && summary.photons < summary.electrons);
+    }
+
+    #[test]
+    fn tot_out_of_range_is_rejected() {
+        for tot in [TotDistribution::Fixed(0), TotDistribution::Fixed(1024), TotDistribution::Uniform { min: 20, max: 10 }, TotDistribution::Uniform { min: 10, max: 2000 }] {
+            assert!(matches!(SimulationBuilder::new().duration(1.0e6).tot(tot).build(), Err(Tp3ErrorKind::SimulationBadSettings)));
+        }
+        assert!(SimulationBuilder::new().duration(1.0e6).tot(TotDistribution::Uniform { min: 1, max: 1023 }).build().is_ok());
+    }
+}
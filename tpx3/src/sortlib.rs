@@ -0,0 +1,235 @@
+//!`sortlib` puts the packets back in time order. Chips are read out independently, so the packets of a
+//!chip can be late by tens of µs compared to the other chips, and a chip is itself locally out of
+//!order. `TimeSorter` holds the electrons and TDCs of each chip for a merge window and emits them
+//!sorted by time, with a bounded latency. Packets without a time are emitted as they come.
+use crate::packetlib::{Packet, StreamPacket};
+use crate::timelib::unwrap_time;
+use crate::auxiliar::value_types::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+///Default merge window, in units of 1.5625 ns (100 µs).
+pub const SORT_WINDOW: TIME = 64_000;
+///A chip behind the newest one by this many windows is idle and no longer holds the others.
+const IDLE_WINDOWS: TIME = 100;
+///Most packets held. Above it, the oldest packets are emitted whatever the window.
+const SORT_CAPACITY: usize = 4_000_000;
+
+struct Pending<P> {
+    time: TIME,
+    order: u64,
+    packet: StreamPacket<P>,
+}
+
+impl<P> PartialEq for Pending<P> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.order) == (other.time, other.order)
+    }
+}
+
+impl<P> Eq for Pending<P> {}
+
+impl<P> PartialOrd for Pending<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P> Ord for Pending<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.time, self.order).cmp(&(other.time, other.order))
+    }
+}
+
+///Bounded-latency time ordering. Times are unwrapped to 64 bits, so the output is monotonic across
+///the electron time overflow. A packet older than one already emitted is emitted at once and counted
+///as late.
+pub struct TimeSorter<P> {
+    window: TIME,
+    pending: BinaryHeap<Reverse<Pending<P>>>,
+    ready: VecDeque<StreamPacket<P>>,
+    latest: Vec<Option<TIME>>,
+    emitted: TIME,
+    order: u64,
+    late: u64,
+}
+
+impl<P: Packet> Default for TimeSorter<P> {
+    fn default() -> Self {
+        TimeSorter::new(SORT_WINDOW)
+    }
+}
+
+impl<P: Packet> TimeSorter<P> {
+    ///`window` is the largest delay of a packet behind its own chip, in units of 1.5625 ns.
+    pub fn new(window: TIME) -> Self {
+        TimeSorter {
+            window,
+            pending: BinaryHeap::new(),
+            ready: VecDeque::new(),
+            latest: Vec::new(),
+            emitted: 0,
+            order: 0,
+            late: 0,
+        }
+    }
+
+    ///Packets emitted out of order.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len() + self.ready.len()
+    }
+
+    ///Unwrapped time of the last packet emitted.
+    pub fn emitted(&self) -> TIME {
+        self.emitted
+    }
+
+    ///Time of the newest packet seen.
+    fn newest(&self) -> Option<TIME> {
+        self.latest.iter().flatten().max().copied()
+    }
+
+    ///Unwraps the time of a packet of the chip `ci` to the 64-bit time closest to the latest one.
+    fn unwrap_time(&mut self, ci: u8, raw: TIME) -> TIME {
+        let ci = ci as usize;
+        if self.latest.len() <= ci {self.latest.resize(ci + 1, None);}
+        let time = match self.latest[ci].or_else(|| self.newest()) {
+            None => raw,
+            Some(reference) => unwrap_time(raw, reference, P::electron_overflow()),
+        };
+        self.latest[ci] = Some(self.latest[ci].map_or(time, |latest| latest.max(time)));
+        time
+    }
+
+    ///Time before which no packet is expected anymore.
+    fn horizon(&self) -> TIME {
+        let newest = match self.newest() {
+            Some(newest) => newest,
+            None => return 0,
+        };
+        let idle = IDLE_WINDOWS * self.window;
+        let oldest = self.latest.iter().flatten().filter(|&&latest| latest + idle >= newest).min().copied().unwrap_or(newest);
+        oldest.saturating_sub(self.window)
+    }
+
+    pub fn push(&mut self, packet: StreamPacket<P>) {
+        let (ci, raw) = match &packet {
+            StreamPacket::Electron(packet) => (packet.ci(), packet.electron_time()),
+            StreamPacket::Tdc(packet) => (packet.ci(), packet.tdc_time_norm()),
+            StreamPacket::Other(_) => {
+                self.ready.push_back(packet);
+                return;
+            },
+        };
+        let time = self.unwrap_time(ci, raw);
+        if time < self.emitted {
+            self.late += 1;
+            self.ready.push_back(packet);
+            return;
+        }
+        self.order += 1;
+        self.pending.push(Reverse(Pending { time, order: self.order, packet }));
+    }
+
+    ///Next packet that can be emitted in order, if any.
+    pub fn pop(&mut self) -> Option<StreamPacket<P>> {
+        if let Some(packet) = self.ready.pop_front() {return Some(packet);}
+        let Reverse(first) = self.pending.peek()?;
+        if first.time <= self.horizon() || self.pending.len() > SORT_CAPACITY {
+            self.pop_any()
+        } else {
+            None
+        }
+    }
+
+    ///Next packet, whatever the window.
+    fn pop_any(&mut self) -> Option<StreamPacket<P>> {
+        if let Some(packet) = self.ready.pop_front() {return Some(packet);}
+        let Reverse(first) = self.pending.pop()?;
+        self.emitted = first.time;
+        Some(first.packet)
+    }
+
+    ///Feeds `packets` and returns the packets ready. Held packets wait for the next call, unless
+    ///`last` is set, in which case every packet is emitted.
+    pub fn sorted<I: Iterator<Item = StreamPacket<P>>>(&mut self, packets: I, last: bool) -> Sorted<'_, P, I> {
+        Sorted { sorter: self, packets, last }
+    }
+}
+
+pub struct Sorted<'a, P, I> {
+    sorter: &'a mut TimeSorter<P>,
+    packets: I,
+    last: bool,
+}
+
+impl<P: Packet, I: Iterator<Item = StreamPacket<P>>> Iterator for Sorted<'_, P, I> {
+    type Item = StreamPacket<P>;
+
+    fn next(&mut self) -> Option<StreamPacket<P>> {
+        loop {
+            if let Some(packet) = self.sorter.pop() {return Some(packet);}
+            match self.packets.next() {
+                Some(packet) => self.sorter.push(packet),
+                None if self.last => return self.sorter.pop_any(),
+                None => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packetlib::{InversePacket, PacketEELS, StreamState};
+    use crate::tdclib::TdcType;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    ///Time in ns of a packet.
+    fn time_ns(packet: &StreamPacket<PacketEELS>) -> f64 {
+        match packet {
+            StreamPacket::Electron(packet) => packet.electron_time() as f64 * 1.5625,
+            StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => packet.tdc_time_norm() as f64 * 1.5625,
+        }
+    }
+
+    #[test]
+    fn chips_are_merged_in_order() {
+        //Four chips, each late by up to 30 µs and locally out of order by up to 40 µs, across the
+        //electron time overflow (~26.8 s).
+        let mut rng = StdRng::seed_from_u64(3);
+        let start = 26_843_000_000;
+        let mut events = (0..20_000usize).map(|index| {
+            let (time, chip) = (start + index * 100, index % 4);
+            (time + chip * 10_000 + rng.gen_range(0..40_000), time, chip)
+        }).collect::<Vec<_>>();
+        events.push((start + 1_000_050, start + 1_000_050, 4));
+        events.sort_unstable();
+        let mut data = Vec::new();
+        for &(_, time, chip) in &events {
+            match chip {
+                4 => data.extend_from_slice(&InversePacket::new_inverse_tdc(time).create_tdc_array(1, TdcType::TdcOneRisingEdge)),
+                _ => data.extend_from_slice(&InversePacket::new_inverse_electron(256 * chip + 10, 20, time).create_electron_array()),
+            }
+        }
+
+        let mut sorter = TimeSorter::<PacketEELS>::new(SORT_WINDOW);
+        let mut stream = StreamState::new();
+        let mut output = Vec::new();
+        let chunks = data.chunks(4_096).count();
+        for (index, chunk) in data.chunks(4_096).enumerate() {
+            output.extend(sorter.sorted(stream.packets::<PacketEELS>(chunk), index + 1 == chunks).map(|packet| time_ns(&packet)));
+        }
+        let overflow = PacketEELS::electron_overflow() as f64 * 1.5625;
+        let output = output.iter().map(|&time| if time < start as f64 - 1.0e6 {time + overflow} else {time}).collect::<Vec<f64>>();
+
+        assert_eq!(output.len(), events.len());
+        assert_eq!(sorter.late(), 0);
+        assert!(output.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
@@ -1,19 +1,29 @@
 //!`speclib` is a collection of tools to set EELS/4D acquisition.
 
-use crate::packetlib::{Packet, PacketEELS as Pack, packet_change};
+use crate::packetlib::{Packet, PacketEELS as Pack, StreamState, StreamPacket};
 use crate::auxiliar::{Settings, misc::TimepixRead};
 //use crate::tdclib::{TdcControl, PeriodicTdcRef};
-use crate::tdclib::{TdcControl, PeriodicTdcRef, isi_box, isi_box::{CHANNELS, IsiBoxTools, IsiBoxHand}};
+use crate::tdclib::{TdcControl, PeriodicTdcRef, TdcRefSet, ELECTRON_TICK, isi_box, isi_box::{CHANNELS, IsiBoxTools, IsiBoxHand}};
 use crate::isi_box_new;
 use crate::errorlib::Tp3ErrorKind;
+use crate::modelib::AcquisitionMode;
+use crate::statslib::{FrameStats, StatsReport};
+use crate::timelib::{Timeline, TimeBase};
+use serde::Serialize;
 use std::time::Instant;
 use std::io::Write;
+use std::net::SocketAddr;
 //use rayon::prelude::*;
 use core::ops::{Add, AddAssign};
 use crate::auxiliar::value_types::*;
 
-const CAM_DESIGN: (POSITION, POSITION) = Pack::chip_array();
 const BUFFER_SIZE: usize = 16384 * 2;
+
+///Frame size, from the detector layout.
+#[inline]
+fn cam_design() -> (POSITION, POSITION) {
+    Pack::chip_array()
+}
 //const SR_TIME: usize = 10_000; //Time window (10_000 -> 10 us);
 //const SR_INDEX: usize = 64; //Maximum x index value to account in the average calculation;
 //const SR_MIN: usize = 0; //Minimum array size to perform the average in super resolution;
@@ -100,9 +110,12 @@ pub trait SpecKind {
     fn build_output(&self) -> &[u8];
     fn build_mut_output(&self) -> &mut [u8];
     fn new(settings: &Settings) -> Self;
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, ref_tdc: &T);
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, ref_tdc: &mut T);
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings);
+    ///`time` is the absolute electron time, in units of 1.5625 ns.
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, settings: &Settings, frame_tdc: &PeriodicTdcRef, ref_tdc: &T);
+    ///`time` is the absolute time of the TDC, in the time base of `ref_tdc`.
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, settings: &Settings, ref_tdc: &mut T);
+    ///`time` is the absolute time of the TDC, in the time base of `frame_tdc`.
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings);
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings);
 }
 
@@ -116,8 +129,8 @@ macro_rules! tp3_vec {
     ($x: expr) => {
         {
             let len = match $x {
-                1 => CAM_DESIGN.0,
-                2 => CAM_DESIGN.1*CAM_DESIGN.0,
+                1 => cam_design().0,
+                2 => cam_design().1*cam_design().0,
                 _ => {panic!("One or two dimensions only!")},
             } as usize;
             let mut temp_vec: Vec<L> = vec![L::zero(); len+1];
@@ -152,16 +165,16 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Live2D, L> {
         SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: Live2D }
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
-        let index = pack.x() + CAM_DESIGN.0 * pack.y();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        let index = pack.x() + cam_design().0 * pack.y();
         add_index!(self, index);
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        add_index!(self, CAM_DESIGN.0-1);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        add_index!(self, cam_design().0-1);
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -187,16 +200,16 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Live1D, L> {
         SpecMeasurement{ data: tp3_vec!(1), aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: Live1D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let index = pack.x();
         add_index!(self, index);
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        add_index!(self, CAM_DESIGN.0-1);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        add_index!(self, cam_design().0-1);
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -222,17 +235,17 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTR2D, L> {
         SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: LiveTR2D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
-        if LiveTR1D::tr_check_if_in(pack.electron_time(), ref_tdc, settings) {
-            let index = pack.x() + CAM_DESIGN.0 * pack.y();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
+        if LiveTR1D::tr_check_if_in(ref_tdc.time_base().convert(time, TimeBase::Electron), ref_tdc, settings) {
+            let index = pack.x() + cam_design().0 * pack.y();
             add_index!(self, index);
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -258,17 +271,17 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTR1D, L> {
         SpecMeasurement{ data: tp3_vec!(1), aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: LiveTR1D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
-        if LiveTR1D::tr_check_if_in(pack.electron_time(), ref_tdc, settings) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
+        if LiveTR1D::tr_check_if_in(ref_tdc.time_base().convert(time, TimeBase::Electron), ref_tdc, settings) {
             let index = pack.x();
             add_index!(self, index);
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -294,18 +307,18 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTilted2D, L> {
         SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: LiveTilted2D }
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let x = pack.x();
         let y = pack.y();
-        let index = x + CAM_DESIGN.0 * y;
+        let index = x + cam_design().0 * y;
         add_index!(self, index);
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        add_index!(self, CAM_DESIGN.0-1);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        add_index!(self, cam_design().0-1);
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -328,26 +341,26 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<FastChrono, L> {
         as_mut_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = (settings.xspim_size*CAM_DESIGN.0) as usize;
+        let len = (settings.xspim_size*cam_design().0) as usize;
         let mut temp_vec = vec![L::zero(); len + 1];
     //type MeasKind;
         temp_vec[len] = L::ten();
         SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: FastChrono}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let line = frame_tdc.counter()/2;
-        let index = pack.x() + line * CAM_DESIGN.0;
+        let index = pack.x() + line * cam_design().0;
         if line < settings.xspim_size {
             add_index!(self, index);
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        add_index!(self, CAM_DESIGN.0-1);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        add_index!(self, cam_design().0-1);
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = (frame_tdc.counter()/2) > settings.xspim_size;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, _settings: &Settings) {
@@ -366,28 +379,28 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Chrono, L> {
         as_mut_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = (settings.xspim_size*CAM_DESIGN.0) as usize;
+        let len = (settings.xspim_size*cam_design().0) as usize;
         let mut temp_vec = vec![L::zero(); len + 1];
         temp_vec[len] = L::ten();
         SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: Chrono}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let line = (frame_tdc.counter()/2) % settings.xspim_size;
-        let index = pack.x() + line * CAM_DESIGN.0;
+        let index = pack.x() + line * cam_design().0;
         add_index!(self, index);
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         let line = frame_tdc.counter() / 2;
         self.is_ready = line % 20 == 0; //Every 20 lines send chrono;
         if line % settings.xspim_size == 0 {
             self.aux_data.push(0); //This indicates the frame must be refreshed;
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        add_index!(self, CAM_DESIGN.0-1);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        add_index!(self, cam_design().0-1);
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, _settings: &Settings) {
         self.is_ready = false;
@@ -411,13 +424,13 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
         as_mut_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len: usize = settings.bytedepth*CAM_DESIGN.0;
+        let len: usize = settings.bytedepth*cam_design().0;
         let mut temp_vec = vec![L::zero(); len + 1];
         temp_vec[len] = L::ten();
         SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: SuperResolution}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: TIME, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let index = pack.x();
         self.aux_data.push(index);
         
@@ -460,14 +473,14 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
             self.aux_data = Vec::new();
         }
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: TIME, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        //append_to_array(&mut self.data, CAM_DESIGN.0-1, settings.bytedepth);
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: TIME, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        //append_to_array(&mut self.data, cam_design().0-1, settings.bytedepth);
+        self.data[cam_design().0-1] = self.data[cam_design().0-1] + L::one();
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
         self.is_ready = false;
@@ -480,6 +493,8 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
 */
 
 impl LiveTR1D {
+    ///Checks if an electron at `ele_time` is inside the time window after the reference. Times,
+    ///delay and width are in the time base of the reference.
     fn tr_check_if_in<T: TdcControl>(ele_time: TIME, ref_tdc: &T, settings: &Settings) -> bool {
         let period = ref_tdc.period().expect("Period must exist in LiveTR1D.");
         let last_time = ref_tdc.time();
@@ -500,13 +515,13 @@ impl LiveTR1D {
 
 impl IsiBoxKind for SpecMeasurement<Live1D, u32> {
     fn isi_new(_settings: &Settings) -> Self {
-        let len = (CAM_DESIGN.0 + CHANNELS as POSITION) as usize;
+        let len = (cam_design().0 + CHANNELS as POSITION) as usize;
         let mut temp_vec: Vec<u32> = vec![0; len+1];
         temp_vec[len] = 10;
         SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, _kind: Live1D }
     }
     fn append_from_isi(&mut self, ext_data: &[u32]) {
-        self.data[CAM_DESIGN.0 as usize..].iter_mut().zip(ext_data.iter()).for_each(|(a, b)| *a+=b);
+        self.data[cam_design().0 as usize..].iter_mut().zip(ext_data.iter()).for_each(|(a, b)| *a+=b);
     }
 }
 
@@ -541,22 +556,27 @@ pub fn run_spectrum<T, V, U, Y>(pack: V, ns: U, my_settings: Settings, frame_tdc
     Ok(my_settings.mode)
 }
     
-fn build_spectrum<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: PeriodicTdcRef, mut ref_tdc: T, mut meas_type: W) -> Result<(), Tp3ErrorKind> 
+fn build_spectrum<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: PeriodicTdcRef, ref_tdc: T, mut meas_type: W) -> Result<(), Tp3ErrorKind> 
     where T: TdcControl,
           V: TimepixRead,
           U: Write,
           W: SpecKind
 {
 
-    let mut last_ci = 0;
+    let mut stream = StreamState::new();
+    let mut timeline = Timeline::default();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
+    let mut stats = FrameStats::new(&frame_tdc);
+    let mut refs = TdcRefSet::new(ref_tdc, &my_settings);
+    let mut frames_sent = 0;
     let start = Instant::now();
 
     while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-        if build_data(&buffer_pack_data[0..size], &mut meas_type, &mut last_ci, &my_settings, &mut frame_tdc, &mut ref_tdc) {
-            let msg = create_header(&my_settings, &frame_tdc, 0);
+        if build_data(stream.packets::<Pack>(&buffer_pack_data[0..size]), &mut meas_type, &mut timeline, &my_settings, &mut frame_tdc, &mut refs, &mut stats) {
+            let msg = create_header(&my_settings, &frame_tdc, frames_sent, &[], &stats.publish(&frame_tdc))?;
+            frames_sent += 1;
             if ns_sock.write(&msg).is_err() {println!("Client disconnected on header."); break;}
-            if ns_sock.write(meas_type.build_output()).is_err() {println!("Client disconnected on data."); break;}
+            if ns_sock.write(meas_type.build_output()).and_then(|_| ns_sock.flush()).is_err() {println!("Client disconnected on data."); break;}
             meas_type.reset_or_else(&frame_tdc, &my_settings);
             if frame_tdc.counter() % 1000 == 0 { let elapsed = start.elapsed(); println!("Total elapsed time is: {:?}. Counter is {}.", elapsed, frame_tdc.counter());};
         }
@@ -566,7 +586,7 @@ fn build_spectrum<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Set
 
 }
 
-pub fn build_spectrum_isi<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: PeriodicTdcRef, mut ref_tdc: T, mut meas_type: W) -> Result<(), Tp3ErrorKind> 
+pub fn build_spectrum_isi<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: PeriodicTdcRef, ref_tdc: T, mut meas_type: W, isibox_addr: SocketAddr) -> Result<(), Tp3ErrorKind> 
     where T: TdcControl,
           V: TimepixRead,
           U: Write,
@@ -574,23 +594,28 @@ pub fn build_spectrum_isi<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_setti
 {
 
     let mut handler = isi_box_new!(spec);
-    handler.bind_and_connect();
+    handler.bind_and_connect(isibox_addr)?;
     handler.configure_scan_parameters(32, 32, 8334);
     handler.configure_measurement_type(false);
     handler.start_threads();
     
-    let mut last_ci = 0;
+    let mut stream = StreamState::new();
+    let mut timeline = Timeline::default();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
+    let mut stats = FrameStats::new(&frame_tdc);
+    let mut refs = TdcRefSet::new(ref_tdc, &my_settings);
+    let mut frames_sent = 0;
     let start = Instant::now();
 
     while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-        if build_data(&buffer_pack_data[0..size], &mut meas_type, &mut last_ci, &my_settings, &mut frame_tdc, &mut ref_tdc) {
+        if build_data(stream.packets::<Pack>(&buffer_pack_data[0..size]), &mut meas_type, &mut timeline, &my_settings, &mut frame_tdc, &mut refs, &mut stats) {
             let x = handler.get_data();
-            let msg = create_header(&my_settings, &frame_tdc, CHANNELS as POSITION);
+            let msg = create_header(&my_settings, &frame_tdc, frames_sent, &isibox_pixels(), &stats.publish(&frame_tdc))?;
+            frames_sent += 1;
             if ns_sock.write(&msg).is_err() {println!("Client disconnected on header."); break;}
             meas_type.append_from_isi(&x);
             let result = meas_type.build_output();
-            if ns_sock.write(result).is_err() {println!("Client disconnected on data."); break;}
+            if ns_sock.write(result).and_then(|_| ns_sock.flush()).is_err() {println!("Client disconnected on data."); break;}
             meas_type.reset_or_else(&frame_tdc, &my_settings);
             if frame_tdc.counter() % 1000 == 0 { let elapsed = start.elapsed(); println!("Total elapsed time is: {:?}. Counter is {}.", elapsed, frame_tdc.counter());};
         }
@@ -601,65 +626,116 @@ pub fn build_spectrum_isi<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_setti
 }
 
 
-fn build_data<T: TdcControl, W: SpecKind>(data: &[u8], final_data: &mut W, last_ci: &mut u8, settings: &Settings, frame_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T) -> bool {
-
-    data.chunks_exact(8).for_each( |x| {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci,
-            _ => {
-                let packet = Pack { chip_index: *last_ci, data: packet_change(x)[0]};
-                
-                match packet.id() {
-                    11 => {
-                        final_data.add_electron_hit(&packet, settings, frame_tdc, ref_tdc);
-                    },
-                    6 if packet.tdc_type() == frame_tdc.id() => {
-                        final_data.upt_frame(&packet, frame_tdc, settings);
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id() => {
-                        final_data.add_tdc_hit(&packet, settings, ref_tdc);
-                    },
-                    _ => {},
-                };
+fn build_data<T: TdcControl, W: SpecKind, I: Iterator<Item = StreamPacket<Pack>>>(packets: I, final_data: &mut W, timeline: &mut Timeline, settings: &Settings, frame_tdc: &mut PeriodicTdcRef, refs: &mut TdcRefSet<T>, stats: &mut FrameStats) -> bool {
+
+    packets.for_each(|packet| {
+        match packet {
+            StreamPacket::Electron(packet) => {
+                stats.add_electron(packet.ci());
+                let time = timeline.electron_time(&packet);
+                if refs.accepts(refs.time_base().convert(time, TimeBase::Electron)) {
+                    final_data.add_electron_hit(&packet, time, settings, frame_tdc, &refs.reference);
+                } else {
+                    stats.add_gated();
+                }
             },
+            StreamPacket::Tdc(packet) if packet.tdc_type() == frame_tdc.id() => {
+                let time = timeline.tdc_time_in(&packet, frame_tdc.time_base());
+                stats.add_frame_tdc(time);
+                final_data.upt_frame(&packet, time, frame_tdc, settings);
+            },
+            StreamPacket::Tdc(packet) if packet.tdc_type() == refs.reference.id() => {
+                stats.add_ref_tdc();
+                let time = timeline.tdc_time_in(&packet, refs.reference.time_base());
+                final_data.add_tdc_hit(&packet, time, settings, &mut refs.reference);
+            },
+            StreamPacket::Tdc(packet) if refs.is_other(packet.tdc_type()) => {
+                let time = timeline.tdc_time_in(&packet, refs.time_base());
+                if let Some(reference) = refs.upt_other(&packet, time) {stats.add_reference_tdc(&packet, reference);}
+            },
+            StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => stats.add_other(&packet),
         };
     });
     final_data.is_ready()
 }
 
 //fn add_isibox_pixels(data: &mut [u8], isi_box_data: [u32; 17]) {
-//    data[CAM_DESIGN.0..].iter_mut().zip(as_bytes(&isi_box_data).iter()).for_each(|(a, b)| *a+=b);
+//    data[cam_design().0..].iter_mut().zip(as_bytes(&isi_box_data).iter()).for_each(|(a, b)| *a+=b);
 //}
 
-fn create_header<T: TdcControl>(set: &Settings, tdc: &T, extra_pixels: POSITION) -> Vec<u8> {
-    let mut msg: String = String::from("{\"timeAtFrame\":");
-    msg.push_str(&(tdc.time().to_string()));
-    msg.push_str(",\"frameNumber\":");
-    msg.push_str(&((tdc.counter()/2).to_string()));
-    msg.push_str(",\"measurementID:\"Null\",\"dataSize\":");
-    if set.mode == 6 || set.mode == 7 { //ChronoMode
-        msg.push_str(&((set.xspim_size*set.bytedepth*(CAM_DESIGN.0+extra_pixels)).to_string()));
-    } else {
-        match set.bin {
-            true => { msg.push_str(&((set.bytedepth*(CAM_DESIGN.0+extra_pixels)).to_string()))},
-            false => { msg.push_str(&((set.bytedepth*(CAM_DESIGN.0+extra_pixels)*CAM_DESIGN.1).to_string()))},
-        }
-    }
-    msg.push_str(",\"bitDepth\":");
-    msg.push_str(&((set.bytedepth<<3).to_string()));
-    msg.push_str(",\"width\":");
-    msg.push_str(&((CAM_DESIGN.0+extra_pixels).to_string()));
-    msg.push_str(",\"height\":");
-    if set.mode == 6 || set.mode == 7 { //ChronoMode
-        msg.push_str(&(set.xspim_size.to_string()));
-    } else {
-        match set.bin {
-            true=>{msg.push_str(&(1.to_string()))},
-            false=>{msg.push_str(&(CAM_DESIGN.1.to_string()))},
-        }
-    }
-    msg.push_str("}\n");
+///Columns appended after the detector columns.
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtraPixels {
+    pub source: &'static str,
+    pub first_column: POSITION,
+    pub count: POSITION,
+}
+
+fn isibox_pixels() -> [ExtraPixels; 1] {
+    [ExtraPixels { source: "IsiBox", first_column: cam_design().0, count: CHANNELS as POSITION }]
+}
+
+///JSON line sent before each frame. Times are in ns. `timeAtFrame` is kept in TDC ticks for older clients.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameHeader<'a> {
+    pub header_version: u8,
+    pub time_at_frame: TIME,
+    ///Half the frame TDC counter, as in the header before it was versioned.
+    pub frame_number: COUNTER,
+    ///Frames sent since the measurement started.
+    pub frames_sent: u64,
+    pub tdc_counter: COUNTER,
+    #[serde(rename = "measurementID")]
+    pub measurement_id: Option<&'a str>,
+    pub data_size: POSITION,
+    pub bit_depth: POSITION,
+    pub width: POSITION,
+    pub height: POSITION,
+    pub mode: u8,
+    pub bin: bool,
+    pub cumul: bool,
+    pub period: f64,
+    pub high_time: f64,
+    pub low_time: f64,
+    pub frame_start: Option<f64>,
+    pub frame_end: Option<f64>,
+    pub electrons: u64,
+    pub extra_pixels: &'a [ExtraPixels],
+    pub stats: &'a StatsReport,
+}
 
-    let s: Vec<u8> = msg.into_bytes();
-    s
+const HEADER_VERSION: u8 = 1;
+
+fn create_header(set: &Settings, tdc: &PeriodicTdcRef, frames_sent: u64, extra_pixels: &[ExtraPixels], stats: &StatsReport) -> Result<Vec<u8>, Tp3ErrorKind> {
+    let lines = AcquisitionMode::find(set.mode).map_or(1, |entry| entry.lines(set));
+    let width = cam_design().0 + extra_pixels.iter().map(|extra| extra.count).sum::<POSITION>();
+    let header = FrameHeader {
+        header_version: HEADER_VERSION,
+        time_at_frame: tdc.time(),
+        frame_number: tdc.counter() / 2,
+        frames_sent,
+        tdc_counter: tdc.counter(),
+        measurement_id: None,
+        data_size: set.bytedepth * width * lines,
+        bit_depth: set.bytedepth << 3,
+        width,
+        height: lines,
+        mode: set.mode,
+        bin: set.bin,
+        cumul: set.cumul,
+        period: tdc.period as f64 * ELECTRON_TICK,
+        high_time: tdc.high_time as f64 * ELECTRON_TICK,
+        low_time: tdc.low_time as f64 * ELECTRON_TICK,
+        frame_start: stats.frame_start,
+        frame_end: stats.frame_end,
+        electrons: stats.electrons.iter().sum(),
+        extra_pixels,
+        stats,
+    };
+
+    let mut msg = serde_json::to_vec(&header).map_err(|_| Tp3ErrorKind::MiscHeaderSerialization)?;
+    msg.push(b'\n');
+    Ok(msg)
 }
@@ -1,22 +1,35 @@
-//!`spimlib` is a collection of tools to set hyperspectral EELS acquisition.
+//!`spimlib` is a collection of tools to set hyperspectral EELS and 4D-STEM acquisition.
 
-use crate::packetlib::{Packet, PacketEELS, packet_change};
+use crate::packetlib::{Packet, PacketEELS, PacketDiffraction, StreamState, StreamPacket};
+use crate::sortlib::TimeSorter;
+use crate::timelib::{Timeline, TimeBase};
 use crate::auxiliar::{Settings, misc::TimepixRead};
-use crate::tdclib::{TdcControl, PeriodicTdcRef, isi_box, isi_box::{IsiBoxTools, IsiBoxHand}};
+use crate::tdclib::{TdcControl, PeriodicTdcRef, TdcRefSet, isi_box, isi_box::{IsiBoxTools, IsiBoxHand}};
 use crate::errorlib::Tp3ErrorKind;
+use crate::statslib::FrameStats;
 use std::time::Instant;
 use crate::isi_box_new;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::sync::mpsc;
 use std::thread;
 use std::convert::TryInto;
 use crate::auxiliar::value_types::*;
+use serde::{Deserialize, Serialize};
 //use rayon::prelude::*;
 
 pub const VIDEO_TIME: TIME = 3200;
-pub const SPIM_PIXELS: POSITION = 1025 + 16;
 const BUFFER_SIZE: usize = 16384 * 2;
 
+///IsiBox channels appended to each spectrum.
+const SPIM_ISI_CHANNELS: POSITION = 16;
+
+///Channels of each spectrum: the detector columns, the TDC column and the IsiBox channels.
+#[inline]
+pub fn spim_pixels() -> POSITION {
+    PacketEELS::chip_array().0 + SPIM_ISI_CHANNELS
+}
+
 
 ///This is little endian
 fn as_bytes<T>(v: &[T]) -> &[u8] {
@@ -28,14 +41,15 @@ fn as_bytes<T>(v: &[T]) -> &[u8] {
 }
 
 ///`SpimKind` is the main trait that measurement types must obey. Custom measurements must all
-///implement these methods.
+///implement these methods. Times are absolute, as given by `Timeline`.
 pub trait SpimKind {
     type MyOutput;
+    type MyPacket: Packet;
 
     fn data(&self) -> &Vec<Self::MyOutput>;
-    fn add_electron_hit(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef);
-    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T);
-    fn upt_line(&self, packet: &PacketEELS, settings: &Settings, line_tdc: &mut PeriodicTdcRef);
+    fn add_electron_hit(&mut self, packet: &Self::MyPacket, time: TIME, line_tdc: &PeriodicTdcRef);
+    fn add_tdc_hit(&mut self, packet: &Self::MyPacket, time: TIME, line_tdc: &PeriodicTdcRef);
+    fn upt_line(&self, packet: &Self::MyPacket, time: TIME, settings: &Settings, line_tdc: &mut PeriodicTdcRef);
     fn check(&self) -> bool;
     fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<POSITION>;
     fn copy_empty(&self) -> Self;
@@ -48,16 +62,17 @@ pub fn get_return_spimindex(x: POSITION, dt: TIME, spim_tdc: &PeriodicTdcRef, xs
     let val = dt % spim_tdc.period;
     let xspim = xspim;
     let yspim = yspim;
-    if val >= spim_tdc.low_time {
+    let scan_time = spim_tdc.scan_time();
+    if val >= scan_time {
         let mut r = (dt / spim_tdc.period) as POSITION; //how many periods -> which line to put.
-        let rin = ((xspim as TIME * (val-spim_tdc.low_time)) / spim_tdc.high_time) as POSITION; //Column correction. Maybe not even needed.
+        let rin = ((xspim as TIME * (val-scan_time)) / spim_tdc.flyback_time()) as POSITION; //Column correction. Maybe not even needed.
             
             if r > (yspim-1) {
                 if r > 4096 {return None;} //This removes overflow electrons. See add_electron_hit
                 r %= yspim;
             }
             
-            let index = (r * xspim + rin) * SPIM_PIXELS + x;
+            let index = (r * xspim + rin) * spim_pixels() + x;
         
             Some(index)
         } else {
@@ -65,25 +80,28 @@ pub fn get_return_spimindex(x: POSITION, dt: TIME, spim_tdc: &PeriodicTdcRef, xs
         }
 }
 
+///Position in the scan (`line * xspim + column`) of an event happening `dt` after the first line.
+///Events during the flyback give `None`. The flyback is the one of the last measured line.
 #[inline]
-pub fn get_spimindex(x: POSITION, dt: TIME, spim_tdc: &PeriodicTdcRef, xspim: POSITION, yspim: POSITION) -> Option<POSITION> {
+pub fn get_scan_position(dt: TIME, spim_tdc: &PeriodicTdcRef, xspim: POSITION, yspim: POSITION) -> Option<POSITION> {
     let val = dt % spim_tdc.period;
-    if val < spim_tdc.low_time {
+    let scan_time = spim_tdc.scan_time();
+    if val < scan_time {
         let mut r = (dt / spim_tdc.period) as POSITION; //how many periods -> which line to put.
-        let rin = ((xspim as TIME * val) / spim_tdc.low_time) as POSITION; //Column correction. Maybe not even needed.
-        //let rin = xspim  * (val / spim_tdc.low_time) as POSITION; //Column correction. Maybe not even needed.
-            
-            if r > (yspim-1) {
-                if r > 4096 {return None;} //This removes overflow electrons. See add_electron_hit
-                r %= yspim;
-            }
-            
-            let index = (r * xspim + rin) * SPIM_PIXELS + x;
-        
-            Some(index)
-        } else {
-            None
+        let rin = ((xspim as TIME * val) / scan_time) as POSITION; //Column correction. Maybe not even needed.
+        if r > (yspim-1) {
+            if r > 4096 {return None;} //This removes overflow electrons. See add_electron_hit
+            r %= yspim;
         }
+        Some(r * xspim + rin)
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub fn get_spimindex(x: POSITION, dt: TIME, spim_tdc: &PeriodicTdcRef, xspim: POSITION, yspim: POSITION) -> Option<POSITION> {
+    get_scan_position(dt, spim_tdc, xspim, yspim).map(|position| position * spim_pixels() + x)
 }
 
 #[inline]
@@ -93,13 +111,13 @@ pub fn get_complete_spimindex(x: POSITION, dt: TIME, spim_tdc: &PeriodicTdcRef,
     let yspim = yspim;
         
     let mut r = (dt / spim_tdc.period) as POSITION; //how many periods -> which line to put.
-    let rin = ((xspim as TIME * val) / spim_tdc.low_time) as POSITION; //Column correction. Maybe not even needed.
+    let rin = ((xspim as TIME * val) / spim_tdc.scan_time()) as POSITION; //Column correction. Maybe not even needed.
             
         if r > (yspim-1) {
             r %= yspim;
         }
             
-        let index = (r * xspim + rin) * SPIM_PIXELS + x;
+        let index = (r * xspim + rin) * spim_pixels() + x;
         
         index
 }
@@ -121,27 +139,26 @@ pub struct Live {
 
 impl SpimKind for Live {
     type MyOutput = (POSITION, TIME);
+    type MyPacket = PacketEELS;
 
     fn data(&self) -> &Vec<(POSITION, TIME)> {
         &self.data
     }
 
     #[inline]
-    fn add_electron_hit(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef) {
-        let ele_time = correct_or_not_etime(packet.electron_time(), line_tdc);
+    fn add_electron_hit(&mut self, packet: &PacketEELS, time: TIME, line_tdc: &PeriodicTdcRef) {
+        let ele_time = correct_or_not_etime(time, line_tdc);
         self.data.push((packet.x(), ele_time - line_tdc.begin_frame - VIDEO_TIME)); //This added the overflow.
     }
     
-    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T) {
-        let tdc_time = packet.tdc_time_norm();
-        ref_tdc.upt(tdc_time, packet.tdc_counter());
+    fn add_tdc_hit(&mut self, _packet: &PacketEELS, tdc_time: TIME, line_tdc: &PeriodicTdcRef) {
         if tdc_time > line_tdc.begin_frame + VIDEO_TIME {
-            self.data.push((SPIM_PIXELS-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
+            self.data.push((spim_pixels()-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
         }
     }
 
-    fn upt_line(&self, packet: &PacketEELS, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
-        line_tdc.upt(packet.tdc_time_norm(), packet.tdc_counter());
+    fn upt_line(&self, packet: &PacketEELS, time: TIME, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        line_tdc.upt(time, packet.tdc_counter());
     }
 
     fn check(&self) -> bool {
@@ -158,9 +175,9 @@ impl SpimKind for Live {
         //index = line * xspim + column
         //
         //To find the actuall index value, one multiply this value by the number of signal pixels
-        //(the spectra) because every spatial point has SPIM_PIXELS channels.
+        //(the spectra) because every spatial point has spim_pixels() channels.
         //
-        //index = index * SPIM_PIXELS
+        //index = index * spim_pixels()
         //
         //With this, we place every electron in the first channel of the signal dimension. We must
         //thus add the pixel address to correct reconstruct the spectral image
@@ -194,21 +211,235 @@ impl SpimKind for Live {
     }
 }
 
+///Maximum number of virtual detectors of a measurement.
+pub const MAX_VIRTUAL_DETECTORS: usize = 8;
+
+///Virtual detector of the 4D-STEM modes. Positions are in detector pixels (see `layoutlib`).
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VirtualDetector {
+    ///Counts the electrons between `inner` and `outer` from `center`. A bright field disk has `inner` 0.
+    Annular { center: (f32, f32), inner: f32, outer: f32 },
+    ///Uses three channels: the electron count, the sum of columns and the sum of rows.
+    CenterOfMass,
+}
+
+impl VirtualDetector {
+    fn channels(&self) -> POSITION {
+        match self {
+            VirtualDetector::Annular { .. } => 1,
+            VirtualDetector::CenterOfMass => 3,
+        }
+    }
+
+    ///Pushes the (channel, weight) pairs of a hit, channels starting at `first`.
+    #[inline]
+    fn add_hit(&self, x: POSITION, y: POSITION, first: POSITION, output: &mut Vec<(POSITION, POSITION)>) {
+        match *self {
+            VirtualDetector::Annular { center, inner, outer } => {
+                let (dx, dy) = (x as f32 - center.0, y as f32 - center.1);
+                let r2 = dx * dx + dy * dy;
+                if r2 >= inner * inner && r2 < outer * outer {output.push((first, 1));}
+            },
+            VirtualDetector::CenterOfMass => {
+                output.push((first, 1));
+                output.push((first + 1, x));
+                output.push((first + 2, y));
+            },
+        }
+    }
+}
+
+///Virtual detectors of a measurement. It has a fixed size so `Settings` stays `Copy`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct VirtualDetectors([Option<VirtualDetector>; MAX_VIRTUAL_DETECTORS]);
+
+impl VirtualDetectors {
+    pub fn new(list: &[VirtualDetector]) -> Result<Self, Tp3ErrorKind> {
+        if list.len() > MAX_VIRTUAL_DETECTORS {return Err(Tp3ErrorKind::SetBadSettings);}
+        let mut detectors = VirtualDetectors::default();
+        detectors.0.iter_mut().zip(list).for_each(|(slot, detector)| *slot = Some(*detector));
+        Ok(detectors)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualDetector> {
+        self.0.iter().flatten()
+    }
+
+    ///Number of images sent: the channels of every detector followed by the TDC channel.
+    pub fn channels(&self) -> POSITION {
+        self.iter().map(|detector| detector.channels()).sum::<POSITION>() + 1
+    }
+}
+
+///`Live4D` records, for each electron, the scan position and the detector pixel hit. The output is a
+///list of (scan position, pixel) pairs, with pixel `width * height` for the reference TDC.
+pub struct Live4D {
+    data: Vec<(POSITION, TIME)>,
+}
+
+///Pixel index on the detector. The index after the last pixel holds the reference TDC.
+#[inline]
+fn diffraction_index(packet: &PacketDiffraction) -> POSITION {
+    packet.x() + PacketDiffraction::chip_array().0 * packet.y()
+}
+
+#[inline]
+fn diffraction_tdc_index() -> POSITION {
+    let (width, height) = PacketDiffraction::chip_array();
+    width * height
+}
+
+impl SpimKind for Live4D {
+    type MyOutput = (POSITION, TIME);
+    type MyPacket = PacketDiffraction;
+
+    fn data(&self) -> &Vec<(POSITION, TIME)> {
+        &self.data
+    }
+
+    #[inline]
+    fn add_electron_hit(&mut self, packet: &PacketDiffraction, time: TIME, line_tdc: &PeriodicTdcRef) {
+        let ele_time = correct_or_not_etime(time, line_tdc);
+        self.data.push((diffraction_index(packet), ele_time - line_tdc.begin_frame - VIDEO_TIME));
+    }
+
+    fn add_tdc_hit(&mut self, _packet: &PacketDiffraction, tdc_time: TIME, line_tdc: &PeriodicTdcRef) {
+        if tdc_time > line_tdc.begin_frame + VIDEO_TIME {
+            self.data.push((diffraction_tdc_index(), tdc_time - line_tdc.begin_frame - VIDEO_TIME))
+        }
+    }
+
+    fn upt_line(&self, packet: &PacketDiffraction, time: TIME, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        line_tdc.upt(time, packet.tdc_counter());
+    }
+
+    fn check(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<POSITION> {
+        let mut output = Vec::with_capacity(self.data.len() * 2);
+        for &(pixel, dt) in &self.data {
+            if let Some(position) = get_scan_position(dt, spim_tdc, set.xspim_size, set.yspim_size) {
+                output.push(position);
+                output.push(pixel);
+            }
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    fn copy_empty(&self) -> Self {
+        Live4D::new()
+    }
+
+    fn new() -> Self {
+        Live4D{ data: Vec::with_capacity(BUFFER_SIZE / 8) }
+    }
+}
+
+///`LiveVirtual` builds the images of the virtual detectors of `Settings`. Each image has one value per
+///scan position; the image of channel `c` starts at index `c * xspim * yspim`. The output is a list
+///of (index, weight) pairs to be added to the images. The last channel counts the reference TDC.
+pub struct LiveVirtual {
+    data: Live4D,
+}
+
+impl SpimKind for LiveVirtual {
+    type MyOutput = (POSITION, TIME);
+    type MyPacket = PacketDiffraction;
+
+    fn data(&self) -> &Vec<(POSITION, TIME)> {
+        self.data.data()
+    }
+
+    #[inline]
+    fn add_electron_hit(&mut self, packet: &PacketDiffraction, time: TIME, line_tdc: &PeriodicTdcRef) {
+        self.data.add_electron_hit(packet, time, line_tdc);
+    }
+
+    fn add_tdc_hit(&mut self, packet: &PacketDiffraction, time: TIME, line_tdc: &PeriodicTdcRef) {
+        self.data.add_tdc_hit(packet, time, line_tdc);
+    }
+
+    fn upt_line(&self, packet: &PacketDiffraction, time: TIME, settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        self.data.upt_line(packet, time, settings, line_tdc);
+    }
+
+    fn check(&self) -> bool {
+        self.data.check()
+    }
+
+    fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<POSITION> {
+        let scan_size = set.xspim_size * set.yspim_size;
+        let width = PacketDiffraction::chip_array().0;
+        let tdc_index = diffraction_tdc_index();
+        let tdc_channel = set.virtual_detectors.channels() - 1;
+        let mut pairs = Vec::new();
+        for &(pixel, dt) in self.data.data() {
+            let position = match get_scan_position(dt, spim_tdc, set.xspim_size, set.yspim_size) {
+                Some(position) => position,
+                None => continue,
+            };
+            let start = pairs.len();
+            if pixel == tdc_index {
+                pairs.push((tdc_channel, 1));
+            } else {
+                let mut first = 0;
+                for detector in set.virtual_detectors.iter() {
+                    detector.add_hit(pixel % width, pixel / width, first, &mut pairs);
+                    first += detector.channels();
+                }
+            }
+            pairs[start..].iter_mut().for_each(|(channel, _)| *channel = *channel * scan_size + position);
+        }
+        pairs.into_iter()
+            .flat_map(|(index, weight)| [index, weight])
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    fn copy_empty(&self) -> Self {
+        LiveVirtual::new()
+    }
+
+    fn new() -> Self {
+        LiveVirtual{ data: Live4D::new() }
+    }
+}
+
 ///Reads timepix3 socket and writes in the output socket a list of frequency followed by a list of unique indexes. First TDC must be a periodic reference, while the second can be nothing, periodic tdc or a non periodic tdc.
-pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut spim_tdc: PeriodicTdcRef, mut ref_tdc: T, meas_type: W) -> Result<(), Tp3ErrorKind>
+pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut spim_tdc: PeriodicTdcRef, ref_tdc: T, meas_type: W) -> Result<(), Tp3ErrorKind>
     where V: 'static + Send + TimepixRead,
           T: 'static + Send + TdcControl,
           W: 'static + Send + SpimKind,
           U: 'static + Send + Write,
 {
     let (tx, rx) = mpsc::channel();
-    let mut last_ci = 0;
+    let mut stream = StreamState::new();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
     let mut list = meas_type.copy_empty();
 
     thread::spawn(move || {
+        let mut sorter = TimeSorter::default();
+        let mut timeline = Timeline::default();
+        let mut stats = FrameStats::new(&spim_tdc);
+        let mut refs = TdcRefSet::new(ref_tdc, &my_settings);
+        let mut frame = spim_tdc.frame();
         while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-            build_spim_data(&mut list, &buffer_pack_data[0..size], &mut last_ci, &my_settings, &mut spim_tdc, &mut ref_tdc);
+            let packets = sorter.sorted(stream.packets(&buffer_pack_data[0..size]), false);
+            build_spim_data(&mut list, packets, &mut timeline, &my_settings, &mut spim_tdc, &mut refs, &mut stats);
+            if spim_tdc.frame() != frame {
+                frame = spim_tdc.frame();
+                stats.publish(&spim_tdc);
+            }
             if tx.send(list).is_err() {println!("Cannot send data over the thread channel."); break;}
             list = meas_type.copy_empty();
         }
@@ -217,7 +448,7 @@ pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Set
     let start = Instant::now();
     for tl in rx {
         let result = tl.build_output(&my_settings, &spim_tdc);
-        if ns_sock.write(as_bytes(&result)).is_err() {println!("Client disconnected on data."); break;}
+        if ns_sock.write(as_bytes(&result)).and_then(|_| ns_sock.flush()).is_err() {println!("Client disconnected on data."); break;}
     }
 
     let elapsed = start.elapsed(); 
@@ -225,26 +456,36 @@ pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Set
     Ok(())
 }
 
-pub fn build_spim_isi<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut spim_tdc: PeriodicTdcRef, mut ref_tdc: T, meas_type: W) -> Result<(), Tp3ErrorKind>
+pub fn build_spim_isi<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut spim_tdc: PeriodicTdcRef, ref_tdc: T, meas_type: W, isibox_addr: SocketAddr) -> Result<(), Tp3ErrorKind>
     where V: 'static + Send + TimepixRead,
           T: 'static + Send + TdcControl,
           W: 'static + Send + SpimKind,
           U: 'static + Send + Write,
 {
     let (tx, rx) = mpsc::channel();
-    let mut last_ci = 0;
+    let mut stream = StreamState::new();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
     let mut list = meas_type.copy_empty();
     
     let mut handler = isi_box_new!(spim);
-    handler.bind_and_connect();
+    handler.bind_and_connect(isibox_addr)?;
     handler.configure_scan_parameters(my_settings.xspim_size.try_into().unwrap(), my_settings.yspim_size.try_into().unwrap(), spim_tdc.pixel_time(my_settings.xspim_size).try_into().unwrap());
     handler.configure_measurement_type(false);
     handler.start_threads();
     
     thread::spawn(move || {
+        let mut sorter = TimeSorter::default();
+        let mut timeline = Timeline::default();
+        let mut stats = FrameStats::new(&spim_tdc);
+        let mut refs = TdcRefSet::new(ref_tdc, &my_settings);
+        let mut frame = spim_tdc.frame();
         while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-            build_spim_data(&mut list, &buffer_pack_data[0..size], &mut last_ci, &my_settings, &mut spim_tdc, &mut ref_tdc);
+            let packets = sorter.sorted(stream.packets(&buffer_pack_data[0..size]), false);
+            build_spim_data(&mut list, packets, &mut timeline, &my_settings, &mut spim_tdc, &mut refs, &mut stats);
+            if spim_tdc.frame() != frame {
+                frame = spim_tdc.frame();
+                stats.publish(&spim_tdc);
+            }
             if tx.send(list).is_err() {println!("Cannot send data over the thread channel."); break;}
             list = meas_type.copy_empty();
         }
@@ -254,9 +495,9 @@ pub fn build_spim_isi<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings:
     for tl in rx {
         let result = tl.build_output(&my_settings, &spim_tdc);
         let x = handler.get_data();
-        if ns_sock.write(as_bytes(&result)).is_err() {println!("Client disconnected on data."); break;}
+        if ns_sock.write(as_bytes(&result)).and_then(|_| ns_sock.flush()).is_err() {println!("Client disconnected on data."); break;}
         if x.len() > 0 {
-            if ns_sock.write(as_bytes(&x)).is_err() {println!("Client disconnected on data."); break;}
+            if ns_sock.write(as_bytes(&x)).and_then(|_| ns_sock.flush()).is_err() {println!("Client disconnected on data."); break;}
         }
     }
 
@@ -266,27 +507,39 @@ pub fn build_spim_isi<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings:
     Ok(())
 }
 
-fn build_spim_data<T: TdcControl, W: SpimKind>(list: &mut W, data: &[u8], last_ci: &mut u8, settings: &Settings, line_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T) {
-
-    data.chunks_exact(8).for_each(|x| {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci,
-            _ => {
-                let packet = PacketEELS { chip_index: *last_ci, data: packet_change(x)[0]};
-                let id = packet.id();
-                match id {
-                    11 => {
-                        list.add_electron_hit(&packet, line_tdc);
-                    },
-                    6 if packet.tdc_type() == line_tdc.id() => {
-                        list.upt_line(&packet, settings, line_tdc);
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id()=> {
-                        list.add_tdc_hit(&packet, line_tdc, ref_tdc);
-                    },
-                    _ => {},
-                };
+///Adds the packets, in time order, to `list`. Times are made absolute by `timeline`.
+fn build_spim_data<T: TdcControl, W: SpimKind, I: Iterator<Item = StreamPacket<W::MyPacket>>>(list: &mut W, packets: I, timeline: &mut Timeline, settings: &Settings, line_tdc: &mut PeriodicTdcRef, refs: &mut TdcRefSet<T>, stats: &mut FrameStats) {
+
+    packets.for_each(|packet| {
+        match packet {
+            StreamPacket::Electron(packet) => {
+                stats.add_electron(packet.ci());
+                let time = timeline.electron_time(&packet);
+                if refs.accepts(refs.time_base().convert(time, TimeBase::Electron)) {
+                    list.add_electron_hit(&packet, time, line_tdc);
+                } else {
+                    stats.add_gated();
+                }
+            },
+            StreamPacket::Tdc(packet) if packet.tdc_type() == line_tdc.id() => {
+                let time = timeline.tdc_time(&packet);
+                stats.add_frame_tdc(time);
+                list.upt_line(&packet, time, settings, line_tdc);
+            },
+            StreamPacket::Tdc(packet) if packet.tdc_type() == refs.reference.id() => {
+                stats.add_ref_tdc();
+                refs.reference.upt(timeline.tdc_time_in(&packet, refs.reference.time_base()), packet.tdc_counter());
+                list.add_tdc_hit(&packet, timeline.tdc_time(&packet), line_tdc);
+            },
+            StreamPacket::Tdc(packet) if refs.is_other(packet.tdc_type()) => {
+                let time = timeline.tdc_time_in(&packet, refs.time_base());
+                if let Some(reference) = refs.upt_other(&packet, time) {stats.add_reference_tdc(&packet, reference);}
+            },
+            StreamPacket::Tdc(packet) if line_tdc.is_opposite_edge(packet.tdc_type()) => {
+                line_tdc.add_opposite_edge(timeline.tdc_time(&packet));
+                stats.add_other(&packet);
             },
+            StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => stats.add_other(&packet),
         };
     });
 }
@@ -0,0 +1,231 @@
+//!`statslib` collects per-frame statistics while packets are decoded. Each report is added to the
+//!frame header sent to the client and published, one JSON line per frame, to the clients of the
+//!stats endpoint (see `listen`).
+use crate::errorlib::Tp3ErrorKind;
+use crate::broadcastlib::Broadcaster;
+use crate::packetlib::{Packet, PacketEELS, PacketKind, IgnoredPackets};
+use crate::tdclib::{TdcControl, PeriodicTdcRef, RoleTdcRef, TdcRole, TdcType, ELECTRON_TICK};
+use crate::layoutlib::layout;
+use crate::auxiliar::value_types::*;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+///Tick of the SPIDR global time, in ns.
+const GLOBAL_TICK: f64 = 25.0;
+
+static STATS_ENDPOINT: OnceLock<Broadcaster> = OnceLock::new();
+
+///Starts the stats endpoint. Can be called once per process; later calls fail with `SetNetworkConfig`.
+pub fn listen(addr: SocketAddr) -> Result<(), Tp3ErrorKind> {
+    if STATS_ENDPOINT.get().is_some() {return Err(Tp3ErrorKind::SetNetworkConfig);}
+    let broadcaster = Broadcaster::new();
+    broadcaster.listen(addr)?;
+    STATS_ENDPOINT.set(broadcaster).map_err(|_| Tp3ErrorKind::SetNetworkConfig)
+}
+
+///Counters of the current frame.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    electrons: Vec<u64>,
+    frame_tdc: u64,
+    ref_tdc: u64,
+    reference_tdcs: Vec<ReferenceCount>,
+    gated: u64,
+    ignored: IgnoredPackets,
+    shutter_open: Option<bool>,
+    shutter_closed: bool,
+    global_lsb: Option<TIME>,
+    global_msb: TIME,
+    overflow_start: COUNTER,
+    missed_start: COUNTER,
+    extra_start: COUNTER,
+    first_frame_time: Option<TIME>,
+    last_frame_time: Option<TIME>,
+    period_sum: TIME,
+    period_count: u64,
+    period_min: TIME,
+    period_max: TIME,
+}
+
+///Edges of an additional reference TDC in a frame.
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceCount {
+    pub tdc: TdcType,
+    pub role: TdcRole,
+    pub count: u64,
+}
+
+///Statistics of a frame. Times are in ns and rates in Hz.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsReport {
+    pub frame: COUNTER,
+    ///Frame TDC that opened the frame. Absent for the first frame.
+    pub frame_start: Option<f64>,
+    ///Last frame TDC of the frame.
+    pub frame_end: Option<f64>,
+    pub duration: f64,
+    ///Electrons of each chip.
+    pub electrons: Vec<u64>,
+    pub electron_rate: Vec<f64>,
+    pub frame_tdc: u64,
+    pub ref_tdc: u64,
+    pub ref_tdc_per_line: f64,
+    ///Additional reference TDCs received, as set in `Settings::reference_tdcs`.
+    pub reference_tdcs: Vec<ReferenceCount>,
+    ///Electrons rejected by the laser windows and the gates of the additional references.
+    pub gated: u64,
+    ///Packets not used by the measurement, by family.
+    pub ignored: IgnoredPackets,
+    ///Shutter state from the last SPIDR shutter packet, if any was received.
+    pub shutter_open: Option<bool>,
+    ///The shutter closed during the frame, so the frame does not cover its whole period.
+    pub shutter_closed: bool,
+    ///SPIDR global time of the last global time packet. It does not roll over with the pixel times.
+    pub global_time: Option<f64>,
+    pub counter_overflows: COUNTER,
+    ///Line edges the hardware did not count, added to the line counter.
+    pub missed_edges: COUNTER,
+    ///Line edges dropped from the line counter.
+    pub extra_edges: COUNTER,
+    ///Running estimates of the line period and of its high time.
+    pub period: f64,
+    pub high_time: f64,
+    ///Scanning time of the last line measured from both edges of the line TDC.
+    pub scan_time: f64,
+    pub measured_period: Option<f64>,
+    pub period_min: Option<f64>,
+    pub period_max: Option<f64>,
+}
+
+impl FrameStats {
+    pub fn new(frame_tdc: &PeriodicTdcRef) -> Self {
+        FrameStats {
+            electrons: vec![0; layout().chips().len()],
+            overflow_start: frame_tdc.counter_overflow(),
+            missed_start: frame_tdc.missed_edges(),
+            extra_start: frame_tdc.extra_edges(),
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn add_electron(&mut self, chip_index: u8) {
+        match self.electrons.get_mut(chip_index as usize) {
+            Some(count) => *count += 1,
+            None => self.ignored.electron += 1,
+        }
+    }
+
+    ///Frame (or line) TDC. Consecutive edges give the measured period.
+    #[inline]
+    pub fn add_frame_tdc(&mut self, time: TIME) {
+        self.frame_tdc += 1;
+        if let Some(last) = self.last_frame_time {
+            let period = (time + PacketEELS::electron_overflow() - last) % PacketEELS::electron_overflow();
+            if self.period_count == 0 || period < self.period_min {self.period_min = period;}
+            if period > self.period_max {self.period_max = period;}
+            self.period_sum += period;
+            self.period_count += 1;
+        }
+        self.last_frame_time = Some(time);
+    }
+
+    #[inline]
+    pub fn add_ref_tdc(&mut self) {
+        self.ref_tdc += 1;
+    }
+
+    ///Edge of an additional reference TDC. The closing edges of the gates are not counted.
+    pub fn add_reference_tdc<P: Packet>(&mut self, packet: &P, reference: &RoleTdcRef) {
+        let tdc = match TdcType::associate_value_to_enum(reference.tdc.tdctype) {
+            Some(tdc) if reference.tdc.tdctype == packet.tdc_type() => tdc,
+            _ => return,
+        };
+        match self.reference_tdcs.iter_mut().find(|count| count.tdc == tdc) {
+            Some(count) => count.count += 1,
+            None => self.reference_tdcs.push(ReferenceCount { tdc, role: reference.role, count: 1 }),
+        }
+    }
+
+    #[inline]
+    pub fn add_gated(&mut self) {
+        self.gated += 1;
+    }
+
+    ///Any packet not used by the measurement.
+    #[inline]
+    pub fn add_other<P: Packet>(&mut self, packet: &P) {
+        match self.ignored.add(packet) {
+            PacketKind::ShutterOpen { .. } => self.shutter_open = Some(true),
+            PacketKind::ShutterClose { .. } => {
+                self.shutter_open = Some(false);
+                self.shutter_closed = true;
+            },
+            PacketKind::GlobalTime { msb: false, time } => self.global_lsb = Some(time),
+            PacketKind::GlobalTime { msb: true, time } => self.global_msb = time,
+            _ => {},
+        }
+    }
+
+    pub fn report(&self, frame_tdc: &PeriodicTdcRef) -> StatsReport {
+        let duration = self.period_sum as f64 * ELECTRON_TICK;
+        let rate = |count: u64| if duration > 0.0 {count as f64 * 1.0e9 / duration} else {0.0};
+        let ticks = |value: TIME| (self.period_count > 0).then_some(value as f64 * ELECTRON_TICK);
+        StatsReport {
+            frame: frame_tdc.counter(),
+            frame_start: self.first_frame_time.map(|time| time as f64 * ELECTRON_TICK),
+            frame_end: self.last_frame_time.map(|time| time as f64 * ELECTRON_TICK),
+            duration,
+            electrons: self.electrons.clone(),
+            electron_rate: self.electrons.iter().map(|&count| rate(count)).collect(),
+            frame_tdc: self.frame_tdc,
+            ref_tdc: self.ref_tdc,
+            ref_tdc_per_line: if self.frame_tdc > 0 {self.ref_tdc as f64 / self.frame_tdc as f64} else {0.0},
+            reference_tdcs: self.reference_tdcs.clone(),
+            gated: self.gated,
+            ignored: self.ignored,
+            shutter_open: self.shutter_open,
+            shutter_closed: self.shutter_closed,
+            global_time: self.global_lsb.map(|lsb| ((self.global_msb << 32) | lsb) as f64 * GLOBAL_TICK),
+            counter_overflows: frame_tdc.counter_overflow() - self.overflow_start,
+            missed_edges: frame_tdc.missed_edges() - self.missed_start,
+            extra_edges: frame_tdc.extra_edges() - self.extra_start,
+            period: frame_tdc.period as f64 * ELECTRON_TICK,
+            high_time: frame_tdc.high_time as f64 * ELECTRON_TICK,
+            scan_time: frame_tdc.scan_time() as f64 * ELECTRON_TICK,
+            measured_period: ticks(self.period_sum / self.period_count.max(1)),
+            period_min: ticks(self.period_min),
+            period_max: ticks(self.period_max),
+        }
+    }
+
+    ///Reports the frame to the stats endpoint, then starts a new one. The last frame TDC is kept,
+    ///so the period across frames is still measured.
+    pub fn publish(&mut self, frame_tdc: &PeriodicTdcRef) -> StatsReport {
+        let report = self.report(frame_tdc);
+        if let Some(endpoint) = STATS_ENDPOINT.get() {
+            if let Ok(mut line) = serde_json::to_vec(&report) {
+                line.push(b'\n');
+                endpoint.send(line);
+            }
+        }
+        *self = FrameStats {
+            electrons: vec![0; self.electrons.len()],
+            overflow_start: frame_tdc.counter_overflow(),
+            missed_start: frame_tdc.missed_edges(),
+            extra_start: frame_tdc.extra_edges(),
+            first_frame_time: self.last_frame_time,
+            last_frame_time: self.last_frame_time,
+            shutter_open: self.shutter_open,
+            shutter_closed: self.shutter_open == Some(false),
+            global_lsb: self.global_lsb,
+            global_msb: self.global_msb,
+            reference_tdcs: self.reference_tdcs.iter().map(|count| ReferenceCount { count: 0, ..*count }).collect(),
+            ..Default::default()
+        };
+        report
+    }
+}
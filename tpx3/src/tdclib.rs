@@ -5,31 +5,43 @@
 mod tdcvec {
     use crate::errorlib::Tp3ErrorKind;
     use crate::tdclib::TdcType;
-    use crate::packetlib::{Packet, PacketEELS as Pack, packet_change};
+    use crate::packetlib::{Packet, PacketEELS as Pack, StreamState, StreamPacket};
+    use crate::timelib::TimeBase;
     use crate::auxiliar::value_types::*;
 
     pub struct TdcSearch<'a> {
         data: Vec<(TIME, TdcType)>,
+        seen: Vec<(TdcType, usize)>,
         how_many: usize,
         tdc_choosen: &'a TdcType,
         initial_counter: Option<COUNTER>,
         last_counter: u16,
+        stream: StreamState,
+        time_base: TimeBase,
     }
 
     impl<'a> TdcSearch<'a> {
-        pub fn new(tdc_choosen: &'a TdcType, how_many: usize) -> Self {
+        ///Times are kept in `time_base`.
+        pub fn new(tdc_choosen: &'a TdcType, how_many: usize, time_base: TimeBase) -> Self {
             TdcSearch{
                 data: Vec::new(),
+                seen: Vec::new(),
                 how_many,
                 tdc_choosen,
                 initial_counter: None,
                 last_counter: 0,
+                stream: StreamState::new(),
+                time_base,
             }
         }
 
+        pub fn time_base(&self) -> TimeBase {
+            self.time_base
+        }
+
         fn add_tdc(&mut self, packet: &Pack) {
             if let Some(tdc) = TdcType::associate_value_to_enum(packet.tdc_type()) {
-                let time = packet.tdc_time_norm();
+                let time = self.time_base.tdc_time(packet);
                 self.data.push( (time, tdc) );
                 if packet.tdc_type() == self.tdc_choosen.associate_value() {
                     self.last_counter = packet.tdc_counter();
@@ -110,14 +122,37 @@ mod tdcvec {
             }
         }
         
+        ///Period from the intervals between the samples. An interval can hold missed edges, so the
+        ///shortest intervals set the period and the others are divided by their number of periods.
+        ///Fails if most intervals are not close to a whole number of periods.
         pub fn find_period(&self) -> Result<TIME, Tp3ErrorKind> {
-            let mut tdc_time = self.get_auto_timelist();
-            let last = tdc_time.pop().expect("Please get at least 02 Tdc's");
-            let before_last = tdc_time.pop().expect("Please get at least 02 Tdc's");
-            if last > before_last {
-                Ok(last - before_last)
-            } else {
-                Err(Tp3ErrorKind::TdcBadPeriod)
+            let tdc_time = self.get_auto_timelist();
+            let mut intervals = tdc_time.windows(2)
+                .filter(|pair| pair[1] > pair[0])
+                .map(|pair| pair[1] - pair[0])
+                .collect::<Vec<TIME>>();
+            if intervals.is_empty() {return Err(Tp3ErrorKind::TdcBadPeriod);}
+            intervals.sort_unstable();
+            let guess = intervals[(intervals.len() - 1) / 2];
+            let periods = intervals.iter()
+                .map(|&interval| (interval, (interval + guess / 2) / guess))
+                .filter(|&(interval, lines)| lines > 0 && interval.abs_diff(lines * guess) * 10 < guess)
+                .collect::<Vec<_>>();
+            if periods.len() * 2 < intervals.len() {return Err(Tp3ErrorKind::TdcBadPeriod);}
+            let (sum, lines) = periods.iter().fold((0, 0), |(sum, count), &(interval, lines)| (sum + interval, count + lines));
+            Ok(sum / lines)
+        }
+
+        ///Hardware counter increments between two edges of the chosen type. Both edges of a line
+        ///are usually counted.
+        pub fn get_counter_step(&self) -> u16 {
+            let count = self.get_counter().unwrap_or(0) as u16;
+            match self.initial_counter {
+                Some(initial) if count > 1 => {
+                    let step = ((self.last_counter + 4096 - initial as u16) % 4096 + (count - 1) / 2) / (count - 1);
+                    step.clamp(1, 2)
+                },
+                _ => 2,
             }
         }
         
@@ -152,18 +187,31 @@ mod tdcvec {
             begin_time
         }
 
+        ///TDCs seen so far, of any line, and their number.
+        pub fn seen(&self) -> &[(TdcType, usize)] {
+            &self.seen
+        }
+
+        fn add_seen(&mut self, packet: &Pack) {
+            if let Some(tdc) = TdcType::associate_value_to_enum(packet.tdc_type()) {
+                match self.seen.iter_mut().find(|(seen, _)| *seen == tdc) {
+                    Some((_, count)) => *count += 1,
+                    None => self.seen.push((tdc, 1)),
+                }
+            }
+        }
+
         pub fn search_specific_tdc(&mut self, data: &[u8]) {
-            data.chunks_exact(8).for_each(|x| {
-                match *x {
-                    [84, 80, 88, 51, _, _, _, _] => {},
-                    _ => {
-                        let packet = Pack {chip_index: 0, data: packet_change(x)[0]};
-                        if packet.id() == 6 && self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
-                            self.add_tdc(&packet);
-                        }
-                    },
-                };
+            let mut stream = std::mem::take(&mut self.stream);
+            stream.packets::<Pack>(data).for_each(|packet| {
+                if let StreamPacket::Tdc(packet) = packet {
+                    self.add_seen(&packet);
+                    if self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
+                        self.add_tdc(&packet);
+                    }
+                }
             });
+            self.stream = stream;
         }
 
     }
@@ -171,6 +219,7 @@ mod tdcvec {
 
 
 ///The four types of TDC's.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TdcType {
     TdcOneRisingEdge,
     TdcOneFallingEdge,
@@ -179,19 +228,6 @@ pub enum TdcType {
     NoTdc,
 }
 
-impl Clone for TdcType {
-    fn clone(&self) -> TdcType {
-        match self {
-            TdcType::TdcOneRisingEdge => TdcType::TdcOneRisingEdge,
-            TdcType::TdcOneFallingEdge => TdcType::TdcOneFallingEdge,
-            TdcType::TdcTwoRisingEdge => TdcType::TdcTwoRisingEdge,
-            TdcType::TdcTwoFallingEdge => TdcType::TdcTwoFallingEdge,
-            TdcType::NoTdc => TdcType::NoTdc,
-        }
-        //match self {
-    }
-}
-
 impl TdcType {
     ///Convenient method. Return value is the 4 bits associated to each TDC.
     pub fn associate_value(&self) -> u8 {
@@ -214,8 +250,28 @@ impl TdcType {
         }
     }
     
+    ///The other edge of the same input line.
+    pub fn opposite_edge(&self) -> TdcType {
+        match *self {
+            TdcType::TdcOneRisingEdge => TdcType::TdcOneFallingEdge,
+            TdcType::TdcOneFallingEdge => TdcType::TdcOneRisingEdge,
+            TdcType::TdcTwoRisingEdge => TdcType::TdcTwoFallingEdge,
+            TdcType::TdcTwoFallingEdge => TdcType::TdcTwoRisingEdge,
+            TdcType::NoTdc => TdcType::NoTdc,
+        }
+    }
+
+    ///Input line of the TDC, 1 or 2, with both edges together. 0 for `NoTdc`.
+    pub fn input_line(&self) -> usize {
+        match *self {
+            TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge => 1,
+            TdcType::TdcTwoRisingEdge | TdcType::TdcTwoFallingEdge => 2,
+            TdcType::NoTdc => 0,
+        }
+    }
+
     ///Check if a given tdc is from the same input line.
-    fn is_same_inputline(&self, check: u8) -> bool {
+    pub fn is_same_inputline(&self, check: u8) -> bool {
         match *self {
             TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge if check == 15 || check == 10 => true,
             TdcType::TdcTwoRisingEdge | TdcType::TdcTwoFallingEdge if check == 14 || check == 11 => true,
@@ -236,9 +292,64 @@ impl TdcType {
 }
 
 use std::time::{Duration, Instant};
+use std::io::{self, Read};
 use crate::errorlib::Tp3ErrorKind;
-use crate::auxiliar::misc::TimepixRead;
+use crate::auxiliar::{Settings, misc::TimepixRead};
 use crate::auxiliar::value_types::*;
+use crate::packetlib::Packet;
+use crate::timelib::TimeBase;
+use serde::{Serialize, Deserialize};
+
+///Duration of a tick of the electron clock, in ns. `Packet::tdc_time` is normalized to it, and the
+///finer TDC clock is `TimeBase::Tdc`.
+pub const ELECTRON_TICK: f64 = 1.5625;
+
+///How the reference TDC behaves. Selects which `TdcControl` implementation is built for it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TdcRefKind {
+    Periodic,
+    SingleTriggerPeriodic,
+    NonPeriodic,
+}
+
+///Builds the frame (or line) TDC and the reference TDC selected in `Settings` and evaluates `$run`
+///with them. The reference implementation is only known at runtime, so `$run` is expanded once for
+///each `TdcControl` type, in the same way `speclib::run_spectrum` does for the bit depth. In `$run`,
+///`$pack` reads again the data read to build the references (see `TdcDiscovery`).
+///
+///# Examples
+///```ignore
+///tdc_dispatch!(my_settings, pack, None, |frame_tdc, ref_tdc| {
+///    speclib::run_spectrum(pack, ns, my_settings, frame_tdc, ref_tdc, speclib::Live1D)?
+///})
+///```
+#[macro_export]
+macro_rules! tdc_dispatch {
+    ($settings: expr, $pack: ident, $ticks_to_frame: expr, |$frame_tdc: ident, $ref_tdc: ident| $run: expr) => {
+        {
+            let mut discovery = $crate::tdclib::TdcDiscovery::new($pack, $settings.tdc_search);
+            let $frame_tdc = <$crate::tdclib::PeriodicTdcRef as $crate::tdclib::TdcControl>::new($settings.frame_tdc, &mut discovery, $ticks_to_frame)?;
+            discovery.set_time_base($settings.time_base);
+            match $settings.ref_tdc_kind {
+                $crate::tdclib::TdcRefKind::Periodic => {
+                    let $ref_tdc = <$crate::tdclib::PeriodicTdcRef as $crate::tdclib::TdcControl>::new($settings.ref_tdc, &mut discovery, None)?;
+                    let $pack = discovery.replay();
+                    $run
+                },
+                $crate::tdclib::TdcRefKind::SingleTriggerPeriodic => {
+                    let $ref_tdc = <$crate::tdclib::SingleTriggerPeriodicTdcRef as $crate::tdclib::TdcControl>::new($settings.ref_tdc, &mut discovery, None)?;
+                    let $pack = discovery.replay();
+                    $run
+                },
+                $crate::tdclib::TdcRefKind::NonPeriodic => {
+                    let $ref_tdc = <$crate::tdclib::NonPeriodicTdcRef as $crate::tdclib::TdcControl>::new($settings.ref_tdc, &mut discovery, None)?;
+                    let $pack = discovery.replay();
+                    $run
+                },
+            }
+        }
+    }
+}
 
 pub trait TdcControl {
     fn id(&self) -> u8;
@@ -246,16 +357,212 @@ pub trait TdcControl {
     fn counter(&self) -> COUNTER;
     fn time(&self) -> TIME;
     fn period(&self) -> Option<TIME>;
-    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut T, sp: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> where Self: Sized;
+    ///Unit of the times of the reference, chosen when it is created.
+    fn time_base(&self) -> TimeBase;
+    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut TdcDiscovery<T>, sp: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> where Self: Sized;
+}
+
+///How the TDC references are searched for. JSON keys are the field names.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TdcSearchOptions {
+    ///TDCs of the chosen type needed to build a periodic reference. At least 2.
+    pub samples: usize,
+    ///Time allowed to find them, in s.
+    pub timeout: f64,
+}
+
+impl Default for TdcSearchOptions {
+    fn default() -> Self {
+        TdcSearchOptions {
+            samples: 3,
+            timeout: 10.0,
+        }
+    }
+}
+
+///Most bytes kept by `TdcDiscovery`. Above it, the discovery fails, as the data read could no longer
+///be handed back whole.
+const DISCOVERY_CAPACITY: usize = 256_000_000;
+
+///Read errors in a row after which the discovery gives up and returns the last one.
+const DISCOVERY_READ_ERRORS: usize = 100;
+
+///Reads the stream while the TDC references are built and keeps what was read. Every reference
+///searches from the first byte kept, and `replay` hands the data back to the measurement, so no
+///packet is lost to the discovery.
+pub struct TdcDiscovery<T> {
+    stream: T,
+    options: TdcSearchOptions,
+    kept: Vec<u8>,
+    position: usize,
+    keep: bool,
+    full: bool,
+    time_base: TimeBase,
 }
 
+impl<T: TimepixRead> TdcDiscovery<T> {
+    pub fn new(stream: T, options: TdcSearchOptions) -> Self {
+        TdcDiscovery {
+            stream,
+            options,
+            kept: Vec::new(),
+            position: 0,
+            keep: true,
+            full: false,
+            time_base: TimeBase::Electron,
+        }
+    }
+
+    ///Time base of the references created from now on.
+    pub fn set_time_base(&mut self, time_base: TimeBase) {
+        self.time_base = time_base;
+    }
+
+    ///Bytes kept so far.
+    pub fn kept(&self) -> usize {
+        self.kept.len()
+    }
+
+    ///Ends the discovery. The data kept is read again, then the stream.
+    pub fn replay(mut self) -> Self {
+        self.position = 0;
+        self.keep = false;
+        self
+    }
+
+    ///Reads until `options.samples` TDCs of `tdc_type` are found, starting from the first byte kept.
+    fn search<'a>(&mut self, tdc_type: &'a TdcType) -> Result<tdcvec::TdcSearch<'a>, Tp3ErrorKind> {
+        let mut buffer_pack_data = vec![0; 16384];
+        let mut tdc_search = tdcvec::TdcSearch::new(tdc_type, self.options.samples.max(2), self.time_base);
+        let timeout = Duration::try_from_secs_f64(self.options.timeout).unwrap_or(Duration::MAX);
+        let start = Instant::now();
+        let mut read_errors = 0;
+        self.position = 0;
+
+        println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
+        loop {
+            let read = self.read_timepix(&mut buffer_pack_data);
+            if read.is_ok() {read_errors = 0;}
+            match read {
+                Ok(_) if self.full => {
+                    println!("***Tdc Lib***: {} bytes were read searching for {}. Discovery is over.", self.kept.len(), tdc_type.associate_str());
+                    return Err(Tp3ErrorKind::TdcDiscoveryFull);
+                },
+                Ok(size) => {
+                    tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
+                    if tdc_search.check_tdc()? {break;}
+                },
+                Err(Tp3ErrorKind::TimepixReadOver) => return Err(Self::not_found(tdc_type, &tdc_search)),
+                Err(error) => {
+                    read_errors += 1;
+                    if read_errors >= DISCOVERY_READ_ERRORS {
+                        println!("***Tdc Lib***: {} read errors in a row searching for {}. Last one is {:?}.", read_errors, tdc_type.associate_str(), error);
+                        return Err(error);
+                    }
+                },
+            }
+            if start.elapsed() > timeout {return Err(Self::not_found(tdc_type, &tdc_search));}
+        }
+        println!("***Tdc Lib***: {} has been found.", tdc_type.associate_str());
+        Ok(tdc_search)
+    }
+
+    fn not_found(tdc_type: &TdcType, tdc_search: &tdcvec::TdcSearch) -> Tp3ErrorKind {
+        let missing = Tp3ErrorKind::TdcNoReceived {
+            missing: *tdc_type,
+            found: tdc_search.get_counter().unwrap_or(0) as usize,
+            seen: tdc_search.seen().to_vec(),
+        };
+        println!("***Tdc Lib***: {:?}.", missing);
+        missing
+    }
+
+    ///TDCs of `tdc_type` in the data kept.
+    fn count_kept(&self, tdc_type: &TdcType) -> usize {
+        let mut tdc_search = tdcvec::TdcSearch::new(tdc_type, 0, self.time_base);
+        tdc_search.search_specific_tdc(&self.kept);
+        tdc_search.get_counter().unwrap_or(0) as usize
+    }
+
+    ///Copies kept data not yet read, by multiples of `align` bytes.
+    fn read_kept(&mut self, buf: &mut [u8], align: usize) -> Option<usize> {
+        let size = (self.kept.len() - self.position).min(buf.len() / align * align);
+        if size == 0 {return None;}
+        buf[..size].copy_from_slice(&self.kept[self.position..self.position + size]);
+        self.position += size;
+        if !self.keep && self.position == self.kept.len() {
+            self.kept = Vec::new();
+            self.position = 0;
+        }
+        Some(size)
+    }
+
+    fn keep_read(&mut self, data: &[u8]) {
+        if !self.keep || self.full {return;}
+        if self.kept.len() + data.len() > DISCOVERY_CAPACITY {
+            self.full = true;
+            return;
+        }
+        self.kept.extend_from_slice(data);
+        self.position = self.kept.len();
+    }
+}
+
+impl<T: TimepixRead> Read for TdcDiscovery<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(size) = self.read_kept(buf, 1) {return Ok(size);}
+        let size = self.stream.read(buf)?;
+        self.keep_read(&buf[..size]);
+        Ok(size)
+    }
+}
+
+impl<T: TimepixRead> TimepixRead for TdcDiscovery<T> {
+    fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+        if let Some(size) = self.read_kept(buf, 8) {return Ok(size);}
+        let size = self.stream.read_timepix(buf)?;
+        self.keep_read(&buf[..size]);
+        Ok(size)
+    }
+}
+
+///Weight of a new interval in the running period and high time, as a power of two.
+const PERIOD_SMOOTHING: u32 = 4;
+
+///A pulse of a TDC input line, from its rising to its falling edge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TdcPulse {
+    pub rise: TIME,
+    pub fall: TIME,
+}
+
+impl TdcPulse {
+    pub fn width(&self) -> TIME {
+        self.fall - self.rise
+    }
+}
+
+///A periodic reference, such as the scan line. The period and the high time follow the signal.
+///An edge the hardware did not count is detected from the time gap and added to the counter, and an
+///edge too close to the previous one is dropped, so the line number stays right.
+///
+///Given the other edge of the same input too, edges are paired into pulses, and each line is
+///measured from its edge (start) to the next other edge (stop).
+///
+///The counter is kept at two counts per line, as when the hardware counts both edges, even if it
+///counts one.
 #[derive(Copy, Clone, Debug)]
 pub struct PeriodicTdcRef {
     tdctype: u8,
     counter: COUNTER,
     counter_offset: COUNTER,
+    counter_step: u16,
+    counter_correction: i64,
     last_hard_counter: u16,
     counter_overflow: COUNTER,
+    missed_edges: COUNTER,
+    extra_edges: COUNTER,
     begin_time: TIME,
     pub ticks_to_frame: Option<COUNTER>,
     pub begin_frame: TIME,
@@ -263,6 +570,12 @@ pub struct PeriodicTdcRef {
     pub high_time: TIME,
     pub low_time: TIME,
     time: TIME,
+    last_edge: Option<TIME>,
+    last_rising: Option<TIME>,
+    line_stop: Option<TIME>,
+    scan_time: Option<TIME>,
+    pulse: Option<TdcPulse>,
+    time_base: TimeBase,
 }
 
 impl TdcControl for PeriodicTdcRef {
@@ -274,9 +587,18 @@ impl TdcControl for PeriodicTdcRef {
         if hard_counter < self.last_hard_counter {
             self.counter_overflow += 1;
         }
+        let counted = (hard_counter + 4096 - self.last_hard_counter) % 4096;
         self.last_hard_counter = hard_counter;
+        //The first edge and the edges after a time rollover can not be checked.
+        if let Some(last_edge) = self.last_edge.filter(|&last_edge| time > last_edge) {
+            if !self.check_edge(time - last_edge, counted) {return;}
+        }
+        if !self.is_rising() {self.add_high_time(time);}
+        self.last_edge = Some(time);
+        self.line_stop = None;
         self.time = time;
-        self.counter = self.last_hard_counter as COUNTER + self.counter_overflow * 4096 - self.counter_offset;
+        let counted = (self.last_hard_counter as i64 + self.counter_overflow as i64 * 4096 - self.counter_offset as i64 + self.counter_correction).max(0) as COUNTER;
+        self.counter = counted * 2 / self.counter_step as COUNTER;
         if let Some(spimy) = self.ticks_to_frame {
             if (self.counter / 2) % spimy == 0 {
                 self.begin_frame = time;
@@ -297,20 +619,12 @@ impl TdcControl for PeriodicTdcRef {
         Some(self.period)
     }
 
-    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut T, ticks_to_frame: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
-        let mut buffer_pack_data = vec![0; 16384];
-        let mut tdc_search = tdcvec::TdcSearch::new(&tdc_type, 3);
-        let start = Instant::now();
+    fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
 
-        println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
-        loop {
-            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived)}
-            if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
-                tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
-                if tdc_search.check_tdc()? {break;}
-            }
-        }
-        println!("***Tdc Lib***: {} has been found.", tdc_type.associate_str());
+    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut TdcDiscovery<T>, ticks_to_frame: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
+        let tdc_search = sock.search(&tdc_type)?;
         let _counter = tdc_search.get_counter()?;
         let counter_offset = tdc_search.get_counter_offset();
         let _last_hard_counter = tdc_search.get_last_hardware_counter();
@@ -318,14 +632,19 @@ impl TdcControl for PeriodicTdcRef {
         let last_time = tdc_search.get_lasttime();
         let high_time = tdc_search.find_high_time()?;
         let period = tdc_search.find_period()?;
+        let high_time = if high_time < period {high_time} else {high_time % period};
         let low_time = period - high_time;
 
         let per_ref = Self {
             tdctype: tdc_type.associate_value(),
             counter: 0,
             counter_offset,
+            counter_step: tdc_search.get_counter_step(),
+            counter_correction: 0,
             last_hard_counter: 0,
             counter_overflow: 0,
+            missed_edges: 0,
+            extra_edges: 0,
             begin_time,
             begin_frame: begin_time,
             ticks_to_frame,
@@ -333,6 +652,12 @@ impl TdcControl for PeriodicTdcRef {
             high_time,
             low_time,
             time: last_time,
+            last_edge: None,
+            last_rising: None,
+            line_stop: None,
+            scan_time: None,
+            pulse: None,
+            time_base: tdc_search.time_base(),
         };
         println!("***TDC Lib***: Creating a new tdc reference: {:?}.", per_ref);
         Ok(per_ref)
@@ -340,6 +665,105 @@ impl TdcControl for PeriodicTdcRef {
 }
 
 impl PeriodicTdcRef {
+    ///Number of times the 12-bit hardware counter wrapped around.
+    pub fn counter_overflow(&self) -> COUNTER {
+        self.counter_overflow
+    }
+
+    ///Edges the hardware did not count, found from the time gaps.
+    pub fn missed_edges(&self) -> COUNTER {
+        self.missed_edges
+    }
+
+    ///Edges dropped, either too close to the previous one or counted without being received.
+    pub fn extra_edges(&self) -> COUNTER {
+        self.extra_edges
+    }
+
+    ///Checks if `tdc_type` is the other edge of the same input line.
+    pub fn is_opposite_edge(&self, tdc_type: u8) -> bool {
+        tdc_type != self.tdctype && TdcType::associate_value_to_enum(self.tdctype).is_some_and(|tdc| tdc.is_same_inputline(tdc_type))
+    }
+
+    ///Start of the current line, its edge.
+    pub fn line_start(&self) -> TIME {
+        self.time
+    }
+
+    ///Stop of the current line, the other edge following its start. None until it is received.
+    pub fn line_stop(&self) -> Option<TIME> {
+        self.line_stop
+    }
+
+    ///Last complete pulse.
+    pub fn last_pulse(&self) -> Option<TdcPulse> {
+        self.pulse
+    }
+
+    ///Scanning time of the last measured line, from its start to its stop. Before any line is
+    ///measured, the low time.
+    pub fn scan_time(&self) -> TIME {
+        self.scan_time.unwrap_or(self.low_time)
+    }
+
+    ///Flyback of the last measured line.
+    pub fn flyback_time(&self) -> TIME {
+        self.period.saturating_sub(self.scan_time())
+    }
+
+    fn is_rising(&self) -> bool {
+        self.tdctype == TdcType::TdcOneRisingEdge.associate_value() || self.tdctype == TdcType::TdcTwoRisingEdge.associate_value()
+    }
+
+    ///Checks an edge `interval` after the previous one, with `counted` hardware counts between them.
+    ///Corrects the counter and follows the period. Returns false if the edge must be dropped.
+    fn check_edge(&mut self, interval: TIME, counted: u16) -> bool {
+        let lines = (interval + self.period / 2) / self.period;
+        let step = self.counter_step as i64;
+        let counted_lines = (counted as i64 + step / 2) / step;
+        if lines == 0 {
+            self.extra_edges += 1;
+            self.counter_correction -= counted as i64;
+            return false;
+        }
+        let lines_i = lines as i64;
+        if lines_i > counted_lines {
+            self.missed_edges += (lines_i - counted_lines) as COUNTER;
+        } else if lines_i < counted_lines {
+            self.extra_edges += (counted_lines - lines_i) as COUNTER;
+        }
+        self.counter_correction += (lines_i - counted_lines) * step;
+
+        let measured = interval / lines;
+        if measured.abs_diff(self.period) * 8 < self.period {
+            self.period = smooth(self.period, measured);
+            self.low_time = self.period.saturating_sub(self.high_time);
+        }
+        true
+    }
+
+    ///Edge of the other type of the same line. The first one after the line start stops the line.
+    pub fn add_opposite_edge(&mut self, time: TIME) {
+        if let Some(start) = self.last_edge.filter(|&start| self.line_stop.is_none() && time > start && time - start < self.period) {
+            self.line_stop = Some(time);
+            self.scan_time = Some(time - start);
+        }
+        match self.is_rising() {
+            true => self.add_high_time(time),
+            false => self.last_rising = Some(time),
+        }
+    }
+
+    ///Follows the high time from a falling edge at `time`.
+    fn add_high_time(&mut self, time: TIME) {
+        let rising = if self.is_rising() {self.last_edge} else {self.last_rising};
+        if let Some(rising) = rising.filter(|&rising| time > rising && time - rising < self.period) {
+            self.pulse = Some(TdcPulse { rise: rising, fall: time });
+            self.high_time = smooth(self.high_time, time - rising);
+            self.low_time = self.period.saturating_sub(self.high_time);
+        }
+    }
+
     pub fn frame(&self) -> COUNTER {
         if let Some(spimy) = self.ticks_to_frame {
             (self.counter / 2) / spimy
@@ -349,7 +773,7 @@ impl PeriodicTdcRef {
     }
 
     pub fn pixel_time(&self, xspim: POSITION) -> TIME {
-        self.low_time / xspim as TIME
+        self.scan_time() / xspim as TIME
     }
 
     pub fn estimate_time(&self) -> TIME {
@@ -357,6 +781,12 @@ impl PeriodicTdcRef {
     }
 }
 
+///Moves `value` towards `new` by a fraction of the difference.
+fn smooth(value: TIME, new: TIME) -> TIME {
+    let change = (new as i64 - value as i64) / (1 << PERIOD_SMOOTHING);
+    (value as i64 + change) as TIME
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct SingleTriggerPeriodicTdcRef {
     tdctype: u8,
@@ -367,6 +797,7 @@ pub struct SingleTriggerPeriodicTdcRef {
     pub begin_frame: TIME,
     pub period: TIME,
     pub time: TIME,
+    time_base: TimeBase,
 }
 
 impl TdcControl for SingleTriggerPeriodicTdcRef {
@@ -395,20 +826,12 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
         Some(self.period)
     }
 
-    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut T, _: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
-        let mut buffer_pack_data = vec![0; 16384];
-        let mut tdc_search = tdcvec::TdcSearch::new(&tdc_type, 3);
-        let start = Instant::now();
+    fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
 
-        println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
-        loop {
-            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived)}
-            if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
-                tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
-                if tdc_search.check_tdc()? {break;}
-            }
-        }
-        println!("***Tdc Lib***: {} has been found.", tdc_type.associate_str());
+    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut TdcDiscovery<T>, _: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
+        let tdc_search = sock.search(&tdc_type)?;
         let counter = tdc_search.get_counter()?;
         let counter_offset = tdc_search.get_counter_offset();
         let last_hard_counter = tdc_search.get_last_hardware_counter();
@@ -426,6 +849,7 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
             begin_frame: begin_time,
             period,
             time: last_time,
+            time_base: tdc_search.time_base(),
         })
     }
 }
@@ -435,6 +859,13 @@ pub struct NonPeriodicTdcRef {
     pub tdctype: u8,
     pub counter: COUNTER,
     pub time: TIME,
+    time_base: TimeBase,
+}
+
+impl NonPeriodicTdcRef {
+    fn is_same_inputline(&self, tdc_type: u8) -> bool {
+        TdcType::associate_value_to_enum(self.tdctype).is_some_and(|tdc| tdc.is_same_inputline(tdc_type))
+    }
 }
 
 impl TdcControl for NonPeriodicTdcRef {
@@ -458,26 +889,163 @@ impl TdcControl for NonPeriodicTdcRef {
     fn period(&self) -> Option<TIME> {
         None
     }
+
+    fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
     
-    fn new<T: TimepixRead>(tdc_type: TdcType, _sock: &mut T, _: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
+    ///Nothing is read: the reference may be rare. The data already read is only checked for it.
+    fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut TdcDiscovery<T>, _: Option<COUNTER>) -> Result<Self, Tp3ErrorKind> {
+        match sock.count_kept(&tdc_type) {
+            0 => println!("***Tdc Lib***: {} not seen yet. Using it as a non periodic reference.", tdc_type.associate_str()),
+            count => println!("***Tdc Lib***: {} seen {} times. Using it as a non periodic reference.", tdc_type.associate_str(), count),
+        }
         Ok(Self {
             tdctype: tdc_type.associate_value(),
             counter: 0,
             time: 0,
+            time_base: sock.time_base,
         })
     }
     
 }
 
+///Use of an additional reference TDC. JSON values are the variant names.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TdcRole {
+    ///Trigger of a pulsed source. Electrons are kept from `time_delay` to `time_delay + time_width`
+    ///after the last trigger.
+    Laser,
+    ///Photon detector. Only counted.
+    Photon,
+    ///Shutter gate. Electrons are kept from its edge to the other edge of the same input.
+    Gate,
+    ///Event of the sample stage. Only counted.
+    Stage,
+}
+
+///An additional reference TDC and its role, as `{"tdc": "TdcTwoFallingEdge", "role": "Photon"}`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TdcRefSpec {
+    pub tdc: TdcType,
+    pub role: TdcRole,
+}
+
+pub const MAX_REFERENCE_TDCS: usize = 4;
+
+///Reference TDCs used besides `ref_tdc`. It has a fixed size so `Settings` stays `Copy`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReferenceTdcs([Option<TdcRefSpec>; MAX_REFERENCE_TDCS]);
+
+impl ReferenceTdcs {
+    ///Each TDC can be used once, and not by one of the `used` TDCs. Neither can the closing edge of a
+    ///gate. The caller lists both edges of the line TDC, as the spim modes pair them.
+    pub fn new(list: &[TdcRefSpec], used: &[TdcType]) -> Result<Self, Tp3ErrorKind> {
+        if list.len() > MAX_REFERENCE_TDCS {return Err(Tp3ErrorKind::SetBadSettings);}
+        for (index, spec) in list.iter().enumerate() {
+            let closing = (spec.role == TdcRole::Gate).then(|| spec.tdc.opposite_edge());
+            let taken = used.contains(&spec.tdc) || closing.is_some_and(|closing| used.contains(&closing))
+                || list[..index].iter().any(|other| other.tdc == spec.tdc);
+            if taken || spec.tdc == TdcType::NoTdc {return Err(Tp3ErrorKind::SetBadSettings);}
+        }
+        let mut tdcs = ReferenceTdcs::default();
+        tdcs.0.iter_mut().zip(list).for_each(|(slot, spec)| *slot = Some(*spec));
+        Ok(tdcs)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TdcRefSpec> {
+        self.0.iter().flatten()
+    }
+}
+
+///An additional reference TDC with its own counts.
+#[derive(Copy, Clone, Debug)]
+pub struct RoleTdcRef {
+    pub role: TdcRole,
+    pub tdc: NonPeriodicTdcRef,
+    open: bool,
+}
+
+///The reference TDC of a measurement together with the additional ones of `Settings::reference_tdcs`.
+///The additional references are counted and gate the electrons depending on their role. Their times
+///are in `Settings::time_base`.
+pub struct TdcRefSet<T> {
+    pub reference: T,
+    others: Vec<RoleTdcRef>,
+    time_base: TimeBase,
+    time_delay: TIME,
+    time_width: TIME,
+}
+
+impl<T: TdcControl> TdcRefSet<T> {
+    pub fn new(reference: T, settings: &Settings) -> Self {
+        let others = settings.reference_tdcs.iter().map(|spec| RoleTdcRef {
+            role: spec.role,
+            tdc: NonPeriodicTdcRef { tdctype: spec.tdc.associate_value(), counter: 0, time: 0, time_base: settings.time_base },
+            open: false,
+        }).collect();
+        TdcRefSet {
+            reference,
+            others,
+            time_base: settings.time_base,
+            time_delay: settings.time_delay,
+            time_width: settings.time_width,
+        }
+    }
+
+    pub fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+
+    pub fn others(&self) -> &[RoleTdcRef] {
+        &self.others
+    }
+
+    ///Checks if `tdc_type` belongs to an additional reference, the closing edge of a gate included.
+    #[inline]
+    pub fn is_other(&self, tdc_type: u8) -> bool {
+        self.others.iter().any(|other| other.tdc.id() == tdc_type || (other.role == TdcRole::Gate && other.tdc.is_same_inputline(tdc_type)))
+    }
+
+    ///Updates the additional reference of `packet` with `time`, in the time base. Returns the
+    ///reference updated, if any.
+    pub fn upt_other<P: Packet>(&mut self, packet: &P, time: TIME) -> Option<&RoleTdcRef> {
+        let tdc_type = packet.tdc_type();
+        let other = self.others.iter_mut().find(|other| other.tdc.id() == tdc_type || (other.role == TdcRole::Gate && other.tdc.is_same_inputline(tdc_type)))?;
+        if other.tdc.id() == tdc_type {
+            other.tdc.upt(time, packet.tdc_counter());
+            other.open = true;
+        } else {
+            other.open = false;
+        }
+        Some(other)
+    }
+
+    ///Checks if an electron at `time`, in the time base, passes the laser windows and the gates.
+    #[inline]
+    pub fn accepts(&self, time: TIME) -> bool {
+        self.others.iter().all(|other| match other.role {
+            TdcRole::Laser => {
+                let start = other.tdc.time() + self.time_delay;
+                other.tdc.counter() > 0 && time >= start && time < start + self.time_width
+            },
+            TdcRole::Gate => other.open,
+            TdcRole::Photon | TdcRole::Stage => true,
+        })
+    }
+}
+
 pub mod isi_box {
     //use rand_distr::{Normal, Distribution};
     //use rand::{thread_rng};
     //use std::fs::OpenOptions;
-    use std::net::TcpStream;
+    use std::net::{TcpStream, SocketAddr};
+    use crate::errorlib::Tp3ErrorKind;
     use std::io::{Read, Write};
     use std::sync::{Arc, Mutex};
     use std::thread;
-    use crate::spimlib::SPIM_PIXELS;
+    use crate::spimlib::spim_pixels;
+    use crate::packetlib::PacketEELS;
 
     pub const CHANNELS: usize = 17;
     
@@ -499,7 +1067,7 @@ pub mod isi_box {
     }
 
     pub trait IsiBoxTools {
-        fn bind_and_connect(&mut self);
+        fn bind_and_connect(&mut self, addr: SocketAddr) -> Result<(), Tp3ErrorKind>;
         fn configure_scan_parameters(&self, xscan: u32, yscan: u32, pixel_time: u32);
         fn configure_measurement_type(&self, save_locally: bool);
         fn new() -> Self;
@@ -540,15 +1108,14 @@ pub mod isi_box {
     macro_rules! impl_bind_connect {
         ($x: ident, $y: ty, $z: tt) => {
             impl IsiBoxTools for $x<$y> {
-                fn bind_and_connect(&mut self) {
+                fn bind_and_connect(&mut self, addr: SocketAddr) -> Result<(), Tp3ErrorKind> {
                     for _ in 0..self.nchannels {
-                        let sock = TcpStream::connect("192.168.198.10:9592").expect("Could not connect to IsiBox.");
-                        //let sock = TcpStream::connect("127.0.0.1:9592").expect("Could not connect to IsiBox.");
+                        let sock = TcpStream::connect(addr).map_err(|_| Tp3ErrorKind::NetConnect(addr))?;
                         self.sockets.push(sock);
                     }
-                    let sock = TcpStream::connect("192.168.198.10:9592").expect("Could not connect to IsiBox.");
-                    //let sock = TcpStream::connect("127.0.0.1:9592").expect("Could not connect to IsiBox.");
+                    let sock = TcpStream::connect(addr).map_err(|_| Tp3ErrorKind::NetConnect(addr))?;
                     self.ext_socket = Some(sock);
+                    Ok(())
                 }
                 fn configure_scan_parameters(&self, xscan: u32, yscan: u32, pixel_time: u32) {
                     let mut config_array: [u32; 3] = [0; 3];
@@ -619,7 +1186,7 @@ pub mod isi_box {
                         let stop_val = stop_arc.lock().unwrap();
                         if *stop_val == true {break;}
                         let mut num = nvec_arclist.lock().unwrap();
-                        as_int(&buffer[0..size]).iter().for_each(|&x| (*num).push((x * SPIM_PIXELS as u32) + 1025 + channel_index));
+                        as_int(&buffer[0..size]).iter().for_each(|&x| (*num).push((x * spim_pixels()) + PacketEELS::chip_array().0 + channel_index));
                     }
                 });
                 if channel_index>0 {channel_index-=1;}
@@ -858,3 +1425,198 @@ pub mod isi_box {
         }
     */
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulatelib::{SimulationBuilder, Simulation};
+    use crate::packetlib::{InversePacket, PacketEELS, StreamState, StreamPacket};
+    use crate::spimlib::VirtualDetectors;
+
+    fn simulation() -> Simulation {
+        SimulationBuilder::new()
+            .scan(16, 8, 1_000.0, 500.0)
+            .frames(3)
+            .beam_current(10.0)
+            .seed(11)
+            .build()
+            .unwrap()
+    }
+
+    ///Line reference locked on the falling edges of the simulated scan.
+    fn line_tdc() -> PeriodicTdcRef {
+        let mut discovery = TdcDiscovery::new(simulation().stream(), TdcSearchOptions::default());
+        PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut discovery, None).unwrap()
+    }
+
+    #[test]
+    fn discovery_replays_the_stream() {
+        let mut expected = Vec::new();
+        simulation().write_to(&mut expected).unwrap();
+
+        let mut discovery = TdcDiscovery::new(simulation().stream(), TdcSearchOptions { samples: 5, timeout: 1.0 });
+        let line = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut discovery, Some(8)).unwrap();
+        NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut discovery, None).unwrap();
+        assert!(line.period > 0 && discovery.kept() > 0);
+
+        let mut data = Vec::new();
+        discovery.replay().read_to_end(&mut data).unwrap();
+        assert!(data == expected);
+    }
+
+    #[test]
+    fn discovery_accepts_any_timeout() {
+        let mut discovery = TdcDiscovery::new(simulation().stream(), TdcSearchOptions { samples: 5, timeout: f64::MAX });
+        assert!(PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut discovery, Some(8)).is_ok());
+    }
+
+    #[test]
+    fn discovery_stops_on_read_errors() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::ConnectionReset))
+            }
+        }
+        impl TimepixRead for Broken {}
+
+        let mut discovery = TdcDiscovery::new(Broken, TdcSearchOptions { samples: 5, timeout: f64::MAX });
+        assert!(matches!(PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut discovery, None), Err(Tp3ErrorKind::TimepixReadLoop)));
+    }
+
+    #[test]
+    fn missing_tdc_reports_what_was_seen() {
+        let mut discovery = TdcDiscovery::new(simulation().stream(), TdcSearchOptions::default());
+        match PeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut discovery, None) {
+            Err(Tp3ErrorKind::TdcNoReceived { missing, found, seen }) => {
+                assert_eq!((missing, found), (TdcType::TdcTwoRisingEdge, 0));
+                assert!(seen.contains(&(TdcType::TdcOneFallingEdge, 3 * 8)));
+            },
+            other => panic!("Unexpected result {:?}.", other.map(|tdc| tdc.period)),
+        }
+    }
+
+    #[test]
+    fn line_counter_follows_missed_and_extra_edges() {
+        let mut line = line_tdc();
+        let (period, step) = (line.period, line.counter_step);
+        let start = 1_000_000;
+        line.upt(start, 100);
+        let first = line.counter();
+        line.upt(start + period, 100 + step);
+        line.upt(start + 4 * period, 100 + 2 * step);
+        line.upt(start + 4 * period + period / 10, 100 + 3 * step);
+        line.upt(start + 5 * period, 100 + 4 * step);
+        assert_eq!((line.missed_edges(), line.extra_edges()), (2, 1));
+        assert_eq!(line.counter(), first + 5 * 2);
+        assert_eq!(line.period, period);
+    }
+
+    #[test]
+    fn frames_follow_lines_counted_once() {
+        let mut line = line_tdc();
+        let period = line.period;
+        (line.counter_step, line.counter_offset, line.ticks_to_frame) = (1, 100, Some(4));
+        let start = 1_000_000;
+        for index in 0..=8 {
+            line.upt(start + index * period, 100 + index as u16);
+            assert_eq!(line.counter(), 2 * index as COUNTER);
+            assert_eq!(line.frame(), index as COUNTER / 4);
+            assert_eq!(line.begin_frame, start + index / 4 * 4 * period);
+        }
+    }
+
+    #[test]
+    fn line_is_measured_from_both_edges() {
+        let mut line = line_tdc();
+        let period = line.period;
+        assert!(line.is_opposite_edge(TdcType::TdcOneRisingEdge.associate_value()));
+        assert!(!line.is_opposite_edge(TdcType::TdcTwoRisingEdge.associate_value()));
+
+        let start = 1_000_000;
+        line.upt(start, 100);
+        line.add_opposite_edge(start + period * 3 / 4);
+        line.add_opposite_edge(start + period * 7 / 8);
+        assert_eq!((line.line_start(), line.line_stop()), (start, Some(start + period * 3 / 4)));
+        assert_eq!((line.scan_time(), line.flyback_time()), (period * 3 / 4, period - period * 3 / 4));
+
+        line.upt(start + period, 100 + line.counter_step);
+        assert_eq!(line.line_stop(), None);
+        assert_eq!(line.last_pulse(), Some(TdcPulse { rise: start + period * 7 / 8, fall: start + period }));
+    }
+
+    #[test]
+    fn reference_tdcs_gate_electrons() {
+        let settings = reference_settings(&[TdcRefSpec { tdc: TdcType::TdcTwoRisingEdge, role: TdcRole::Laser },
+            TdcRefSpec { tdc: TdcType::TdcTwoFallingEdge, role: TdcRole::Photon }]);
+
+        let mut refs = TdcRefSet::new(NonPeriodicTdcRef { tdctype: settings.ref_tdc.associate_value(), counter: 0, time: 0, time_base: settings.time_base }, &settings);
+        assert!(!refs.accepts(1_600));
+        //Laser at 1600 ticks of 1.5625 ns, then a photon.
+        let mut data = InversePacket::new_inverse_tdc(2_500).create_tdc_array(1, TdcType::TdcTwoRisingEdge).to_vec();
+        data.extend_from_slice(&InversePacket::new_inverse_tdc(3_000).create_tdc_array(1, TdcType::TdcTwoFallingEdge));
+        for packet in StreamState::new().packets::<PacketEELS>(&data) {
+            if let StreamPacket::Tdc(packet) = packet {
+                assert!(refs.is_other(packet.tdc_type()));
+                let time = refs.time_base().tdc_time(&packet);
+                refs.upt_other(&packet, time);
+            }
+        }
+        assert!(!refs.accepts(1_699) && refs.accepts(1_700) && refs.accepts(1_749) && !refs.accepts(1_750));
+        let counts = refs.others().iter().map(|other| (other.role, other.tdc.counter())).collect::<Vec<_>>();
+        assert_eq!(counts, vec![(TdcRole::Laser, 1), (TdcRole::Photon, 1)]);
+    }
+
+    #[test]
+    fn gate_opens_and_closes_with_its_edges() {
+        let settings = reference_settings(&[TdcRefSpec { tdc: TdcType::TdcTwoRisingEdge, role: TdcRole::Gate }]);
+        let mut refs = TdcRefSet::new(NonPeriodicTdcRef { tdctype: settings.ref_tdc.associate_value(), counter: 0, time: 0, time_base: settings.time_base }, &settings);
+        assert!(!refs.accepts(1_000));
+        let mut data = InversePacket::new_inverse_tdc(2_500).create_tdc_array(1, TdcType::TdcTwoRisingEdge).to_vec();
+        data.extend_from_slice(&InversePacket::new_inverse_tdc(3_000).create_tdc_array(1, TdcType::TdcTwoFallingEdge));
+        let mut open = Vec::new();
+        for packet in StreamState::new().packets::<PacketEELS>(&data) {
+            if let StreamPacket::Tdc(packet) = packet {
+                assert!(refs.is_other(packet.tdc_type()));
+                let time = refs.time_base().tdc_time(&packet);
+                refs.upt_other(&packet, time);
+                open.push(refs.accepts(time));
+            }
+        }
+        assert_eq!(open, vec![true, false]);
+        assert_eq!(refs.others()[0].tdc.counter(), 1);
+    }
+
+    #[test]
+    fn line_edges_are_not_references() {
+        let line = [TdcType::TdcOneRisingEdge, TdcType::TdcOneFallingEdge, TdcType::TdcTwoFallingEdge];
+        for role in [TdcRole::Photon, TdcRole::Gate] {
+            let list = [TdcRefSpec { tdc: TdcType::TdcOneFallingEdge, role }];
+            assert!(matches!(ReferenceTdcs::new(&list, &line), Err(Tp3ErrorKind::SetBadSettings)));
+        }
+    }
+
+    fn reference_settings(list: &[TdcRefSpec]) -> Settings {
+        Settings {
+            bin: false,
+            bytedepth: 4,
+            cumul: false,
+            mode: 0,
+            xspim_size: 512,
+            yspim_size: 512,
+            xscan_size: 512,
+            yscan_size: 512,
+            time_delay: 100,
+            time_width: 50,
+            spimoverscanx: 1,
+            spimoverscany: 1,
+            frame_tdc: TdcType::TdcOneRisingEdge,
+            ref_tdc: TdcType::TdcOneFallingEdge,
+            ref_tdc_kind: TdcRefKind::NonPeriodic,
+            tdc_search: TdcSearchOptions::default(),
+            time_base: TimeBase::default(),
+            reference_tdcs: ReferenceTdcs::new(list, &[TdcType::TdcOneRisingEdge, TdcType::TdcOneFallingEdge]).unwrap(),
+            virtual_detectors: VirtualDetectors::default(),
+        }
+    }
+}
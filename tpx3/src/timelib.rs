@@ -0,0 +1,236 @@
+//!`timelib` reconstructs absolute 64-bit times. The electron time rolls over every
+//!`Packet::electron_overflow` (~26.8 s), and so does the TDC time once normalized. `Timeline` counts
+//!the rollovers of the electrons and of each TDC input line independently and returns times that
+//!keep growing across them, so acquisitions of any length share one time base.
+//!
+//!`TimeBase` is the unit of the times of a measurement, either the electron clock or the finer TDC
+//!clock. Conversions between the two clocks are done here only.
+use crate::packetlib::{Packet, PacketEELS};
+use crate::tdclib::{TdcType, ELECTRON_TICK};
+use crate::auxiliar::value_types::*;
+use serde::{Deserialize, Serialize};
+
+///Ratio between the 1.5625 ns tick and the 260 ps tick of `Packet::tdc_time_abs`.
+const FINE_TICKS: TIME = 6;
+
+///Unit of the times. JSON values are the variant names.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeBase {
+    ///1.5625 ns, the electron clock. TDCs lose their fine time.
+    #[default]
+    Electron,
+    ///260 ps, the TDC clock. Electron times are scaled.
+    Tdc,
+}
+
+impl TimeBase {
+    ///Ticks of this base in one 1.5625 ns tick.
+    #[inline]
+    pub fn scale(self) -> TIME {
+        match self {
+            TimeBase::Electron => 1,
+            TimeBase::Tdc => FINE_TICKS,
+        }
+    }
+
+    ///Duration of a tick, in ns.
+    pub fn tick(self) -> f64 {
+        ELECTRON_TICK / self.scale() as f64
+    }
+
+    pub fn to_ns(self, time: TIME) -> f64 {
+        time as f64 * self.tick()
+    }
+
+    pub fn from_ns(self, time: f64) -> TIME {
+        (time / self.tick()).round() as TIME
+    }
+
+    ///`time`, given in `base`, in this base.
+    #[inline]
+    pub fn convert(self, time: TIME, base: TimeBase) -> TIME {
+        time * self.scale() / base.scale()
+    }
+
+    ///Rollover period of `electron_time` and `tdc_time`.
+    pub fn overflow(self) -> TIME {
+        PacketEELS::electron_overflow() * self.scale()
+    }
+
+    ///`Packet::electron_time` in this base.
+    #[inline]
+    pub fn electron_time<P: Packet>(self, packet: &P) -> TIME {
+        packet.electron_time() * self.scale()
+    }
+
+    ///TDC time in this base, rolling over with the electron time.
+    #[inline]
+    pub fn tdc_time<P: Packet>(self, packet: &P) -> TIME {
+        match self {
+            TimeBase::Electron => packet.tdc_time_norm(),
+            TimeBase::Tdc => packet.tdc_time_abs_norm(),
+        }
+    }
+}
+
+const ELECTRON: usize = 0;
+const OTHER_TDC: usize = 3;
+
+///64-bit time of the wrapped time `raw` that is closest to `reference`. `raw` rolls over every
+///`period`.
+#[inline]
+pub fn unwrap_time(raw: TIME, reference: TIME, period: TIME) -> TIME {
+    let time = reference - reference % period + raw;
+    if time > reference + period / 2 && time >= period {time - period}
+    else if time + period / 2 < reference {time + period}
+    else {time}
+}
+
+///Source of a TDC: its input line, edges together.
+fn tdc_source(tdc_type: u8) -> usize {
+    TdcType::associate_value_to_enum(tdc_type).map_or(OTHER_TDC, |tdc| tdc.input_line())
+}
+
+///Absolute times of the electrons and TDCs. A packet can be out of order by up to half a period.
+#[derive(Copy, Clone, Debug)]
+pub struct Timeline {
+    period: TIME,
+    latest: [Option<TIME>; OTHER_TDC + 1],
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new(PacketEELS::electron_overflow())
+    }
+}
+
+impl Timeline {
+    ///`period` is the rollover period of the times, in units of 1.5625 ns.
+    pub fn new(period: TIME) -> Self {
+        Timeline {
+            period,
+            latest: [None; OTHER_TDC + 1],
+        }
+    }
+
+    ///Unwraps `raw`, given in units of 1.5625 ns / `scale`. A source seen for the first time starts
+    ///from the newest time of the others, so every source counts the same rollovers.
+    fn unwrap(&mut self, source: usize, raw: TIME, scale: TIME) -> TIME {
+        let reference = self.latest[source].or_else(|| self.latest.iter().flatten().max().copied());
+        let time = match reference {
+            Some(reference) => unwrap_time(raw, reference * scale, self.period * scale),
+            None => raw,
+        };
+        let coarse = time / scale;
+        self.latest[source] = Some(self.latest[source].map_or(coarse, |latest| latest.max(coarse)));
+        time
+    }
+
+    ///Absolute `Packet::electron_time`, in units of 1.5625 ns.
+    #[inline]
+    pub fn electron_time<P: Packet>(&mut self, packet: &P) -> TIME {
+        self.unwrap(ELECTRON, packet.electron_time(), 1)
+    }
+
+    ///Absolute `Packet::tdc_time_norm`, in units of 1.5625 ns.
+    #[inline]
+    pub fn tdc_time<P: Packet>(&mut self, packet: &P) -> TIME {
+        self.tdc_time_in(packet, TimeBase::Electron)
+    }
+
+    ///Absolute `Packet::tdc_time_abs_norm`, in units of 260 ps.
+    #[inline]
+    pub fn tdc_time_abs<P: Packet>(&mut self, packet: &P) -> TIME {
+        self.tdc_time_in(packet, TimeBase::Tdc)
+    }
+
+    ///Absolute electron time in `base`.
+    #[inline]
+    pub fn electron_time_in<P: Packet>(&mut self, packet: &P, base: TimeBase) -> TIME {
+        self.electron_time(packet) * base.scale()
+    }
+
+    ///Absolute TDC time in `base`.
+    #[inline]
+    pub fn tdc_time_in<P: Packet>(&mut self, packet: &P, base: TimeBase) -> TIME {
+        self.unwrap(tdc_source(packet.tdc_type()), base.tdc_time(packet), base.scale())
+    }
+
+    ///Rollovers of the electron time so far.
+    pub fn electron_rollovers(&self) -> COUNTER {
+        self.rollovers(ELECTRON)
+    }
+
+    ///Rollovers of the input line of `tdc_type` so far.
+    pub fn tdc_rollovers(&self, tdc_type: TdcType) -> COUNTER {
+        self.rollovers(tdc_source(tdc_type.associate_value()))
+    }
+
+    fn rollovers(&self, source: usize) -> COUNTER {
+        self.latest[source].map_or(0, |latest| (latest / self.period) as COUNTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packetlib::{InversePacket, StreamState, StreamPacket};
+
+    fn decode(data: &[u8]) -> Vec<StreamPacket<PacketEELS>> {
+        StreamState::new().packets::<PacketEELS>(data).collect()
+    }
+
+    #[test]
+    fn electrons_and_lines_share_rollovers() {
+        //Two minutes of a line TDC every 100 ms, a photon TDC every 70 ms and electrons every 30 ms.
+        //Each source rolls over at its own moment and some electrons come slightly out of order.
+        let mut data = Vec::new();
+        let mut events = (0..4_000usize).map(|index| (index * 30_000_000 + (index % 3) * 50_000, 0))
+            .chain((0..1_200).map(|index| (index * 100_000_000 + 10_000, 1)))
+            .chain((0..1_700).map(|index| (index * 70_000_000 + 20_000, 2)))
+            .collect::<Vec<_>>();
+        events.sort_unstable_by_key(|&(time, _)| time - time % 1_000_000);
+        for &(time, source) in &events {
+            match source {
+                0 => data.extend_from_slice(&InversePacket::new_inverse_electron(300, 20, time).create_electron_array()),
+                1 => data.extend_from_slice(&InversePacket::new_inverse_tdc(time).create_tdc_array(1, TdcType::TdcOneFallingEdge)),
+                _ => data.extend_from_slice(&InversePacket::new_inverse_tdc(time).create_tdc_array(1, TdcType::TdcTwoRisingEdge)),
+            }
+        }
+
+        let mut timeline = Timeline::default();
+        let times = decode(&data).iter().map(|packet| match packet {
+            StreamPacket::Electron(packet) => timeline.electron_time(packet) as f64 * 1.5625,
+            StreamPacket::Tdc(packet) | StreamPacket::Other(packet) => timeline.tdc_time_abs(packet) as f64 * 1.5625 / 6.0,
+        }).collect::<Vec<f64>>();
+
+        assert_eq!(times.len(), events.len());
+        for (&time, &(expected, _)) in times.iter().zip(events.iter()) {
+            assert!((time - expected as f64).abs() < 2.0, "{} != {}", time, expected);
+        }
+        assert_eq!(timeline.electron_rollovers(), 4);
+        assert_eq!(timeline.tdc_rollovers(TdcType::TdcOneRisingEdge), 4);
+        assert_eq!(timeline.tdc_rollovers(TdcType::TdcTwoFallingEdge), 4);
+    }
+
+    #[test]
+    fn time_bases_agree() {
+        let time = 30_000_000_123;
+        let mut data = InversePacket::new_inverse_tdc(time).create_tdc_array(1, TdcType::TdcOneRisingEdge).to_vec();
+        data.extend_from_slice(&InversePacket::new_inverse_electron(10, 20, time).create_electron_array());
+        let packets = decode(&data);
+        let (tdc, electron) = match (&packets[0], &packets[1]) {
+            (StreamPacket::Tdc(tdc), StreamPacket::Electron(electron)) => (tdc, electron),
+            _ => panic!("Unexpected packets."),
+        };
+
+        let wrapped = (time % (PacketEELS::electron_overflow() as usize * 25 / 16)) as f64;
+        for base in [TimeBase::Electron, TimeBase::Tdc] {
+            assert!((base.to_ns(base.tdc_time(tdc)) - wrapped).abs() < base.tick());
+            assert_eq!(base.electron_time(electron), base.convert(electron.electron_time(), TimeBase::Electron));
+            assert_eq!(base.from_ns(base.to_ns(base.tdc_time(tdc))), base.tdc_time(tdc));
+        }
+        let mut timeline = Timeline::default();
+        assert!((TimeBase::Tdc.to_ns(timeline.tdc_time_in(tdc, TimeBase::Tdc)) - wrapped).abs() < TimeBase::Tdc.tick());
+    }
+}